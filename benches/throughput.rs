@@ -0,0 +1,136 @@
+//! Throughput of checking parsed log lines against a query, looking fields up by name or by slot
+//!
+//! Run with `cargo bench`, which prints the lines checked per second for each way, relative to
+//! a tree of boxed checks that look fields up in a map, like queries were checked before programs.
+use log_query::{process_query_on_log_line, LogLineParseResult, Parser, Query, SimpleParser};
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::time::Instant;
+
+/// Numbers of lines to check and how many times to check them, first few enough to stay in
+/// the CPU caches and then too many to fit
+const SIZES: [(usize, usize); 2] = [(2_000, 200), (200_000, 2)];
+const TRIES: usize = 5;
+
+const LINE_FORMAT: &str = r"(?P<year>\d{4})/(?P<month>\d{2})/(?P<day>\d{2})\s(?P<hour>\d{2}):(?P<minute>\d{2}):(?P<second>\d{2})\.(?P<millisecond>\d{0,3})\s(?P<verbosity>\w+)\s\[(?P<class>\w+)\]\s\[(?P<thread>[^\]]+)\]\s\[(?P<application>[^\]]+)\]\s\[(?P<client_id>[^\]]*)\]\s(?P<content>.*)";
+
+const QUERY: &str = "verbosity in (WARN, ERROR) && class = \"ImageManagerImpl\" || thread startswith \"worker-1\" && !content contains \"heartbeat\"";
+
+fn log_lines(lines: usize) -> Vec<String> {
+    let verbosities = ["DEBUG", "INFO", "WARN", "ERROR"];
+    let classes = ["ImageManagerImpl", "SessionStore", "RequestRouter"];
+    (0..lines).map(|index| format!(
+        "2020/07/17 23:{:02}:{:02}.{:03} {} [{}] [worker-{}] [liquid-server-war] [client{}] Request {} handled, heartbeat {}",
+        index / 60 % 60, index % 60, index % 1000,
+        verbosities[index % verbosities.len()],
+        classes[index % classes.len()],
+        index % 16,
+        index % 100,
+        index,
+        index % 7 == 0,
+    )).collect()
+}
+
+/// A node of a constraint tree, checked through dynamic dispatch like the constraints were
+type Check = Box<dyn Fn(&dyn LogLineParseResult) -> bool>;
+
+/// Check a field looked up in the captures by name, like the constraints did before slots
+fn field(name: &str, holds: impl Fn(&str) -> bool + 'static) -> Check {
+    let name = String::from(name);
+    Box::new(move |line| line.get_content().get(&name).map_or(false, |value| holds(value)))
+}
+
+fn equals(expected: &str) -> impl Fn(&str) -> bool {
+    let expected = String::from(expected);
+    move |value| value == expected
+}
+
+fn starts_with(prefix: &str) -> impl Fn(&str) -> bool {
+    let prefix = String::from(prefix);
+    move |value| value.starts_with(prefix.as_str())
+}
+
+fn contains(needle: &str) -> impl Fn(&str) -> bool {
+    let needle = String::from(needle);
+    move |value| value.contains(needle.as_str())
+}
+
+fn all(operands: Vec<Check>) -> Check {
+    Box::new(move |line| operands.iter().all(|operand| operand(line)))
+}
+
+fn any(operands: Vec<Check>) -> Check {
+    Box::new(move |line| operands.iter().any(|operand| operand(line)))
+}
+
+fn not(inner: Check) -> Check {
+    Box::new(move |line| !inner(line))
+}
+
+/// `QUERY` as a tree of boxed checks with fields looked up in a map of the captures
+fn constraint_tree() -> Check {
+    let verbosities: HashSet<String> = ["WARN", "ERROR"].iter().map(|verbosity| String::from(*verbosity)).collect();
+    any(vec![
+        all(vec![
+            field("verbosity", move |value| verbosities.contains(value)),
+            field("class", equals("ImageManagerImpl")),
+        ]),
+        all(vec![
+            field("thread", starts_with("worker-1")),
+            not(field("content", contains("heartbeat"))),
+        ]),
+    ])
+}
+
+/// Check every line once per round, returning the number of matches and the lines checked per second
+fn measure(check: &dyn Fn(&dyn LogLineParseResult) -> bool, parsed: &[Box<dyn LogLineParseResult>], rounds: usize) -> (usize, f64) {
+    let start = Instant::now();
+    let mut matches = 0;
+    for _ in 0..rounds {
+        matches += parsed.iter().filter(|line| check(black_box(&***line))).count();
+    }
+    (matches, (rounds * parsed.len()) as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Measure every way of checking in turn, several times, keeping the best throughput of each
+/// so that a slow moment of the machine does not count against one of them
+fn compare(checks: &[&dyn Fn(&dyn LogLineParseResult) -> bool], parsed: &[Box<dyn LogLineParseResult>], rounds: usize) -> Vec<f64> {
+    let mut best = vec![0.0; checks.len()];
+    let mut matches = vec![0; checks.len()];
+    for _ in 0..TRIES {
+        for (index, check) in checks.iter().enumerate() {
+            let (found, throughput) = measure(*check, parsed, rounds);
+            matches[index] = found;
+            best[index] = f64::max(best[index], throughput);
+        }
+    }
+    assert!(matches.iter().all(|found| *found == matches[0]), "Every way finds the same lines");
+    best
+}
+
+fn main() {
+    let parser = SimpleParser::new("bench_parser", LINE_FORMAT).unwrap();
+    let tree = constraint_tree();
+    let by_name_query = Query::new(QUERY).unwrap();
+    let by_slot_query = Query::new(QUERY).unwrap().resolve_fields(&parser);
+    for (lines, rounds) in SIZES.iter() {
+        let parsed: Vec<Box<dyn LogLineParseResult>> = log_lines(*lines).iter()
+            .map(|line| parser.parse(line).unwrap() as Box<dyn LogLineParseResult>)
+            .collect();
+        // the captures used to be collected into a map while parsing, so that is not measured
+        for line in parsed.iter() {
+            black_box(line.get_content());
+        }
+
+        let by_tree = |line: &dyn LogLineParseResult| tree(line);
+        let by_name = |line: &dyn LogLineParseResult| process_query_on_log_line(&by_name_query, line);
+        let by_slot = |line: &dyn LogLineParseResult| process_query_on_log_line(&by_slot_query, line);
+        let best = compare(&[&by_tree, &by_name, &by_slot], &parsed, *rounds);
+        let (by_tree, by_name, by_slot) = (best[0], best[1], best[2]);
+
+        println!("{} lines", lines);
+        println!("  constraint tree: {:>12.0} lines/s", by_tree);
+        println!("  fields by name:  {:>12.0} lines/s ({:.2}x)", by_name, by_name / by_tree);
+        println!("  fields by slot:  {:>12.0} lines/s ({:.2}x)", by_slot, by_slot / by_tree);
+    }
+}
@@ -11,7 +11,14 @@ use crate::parser::*;
 use crate::query::*;
 pub use crate::parser::simple_parser::SimpleParser;
 pub use crate::parser::log_line_parse_result::LogLineParseResult;
+pub use crate::parser::timestamp::Timestamp;
+pub use crate::parser::verbosity::Verbosity;
 pub use crate::query::simple_query::Query;
+pub use crate::query::builder::{FieldBuilder, QueryBuilder};
+pub use crate::query::functions::{QueryFunction, QueryFunctions};
+pub use crate::query::saved_queries::SavedQueries;
+pub use crate::query::context::QueryContext;
+pub use crate::query::pipeline::{Pipeline, Record};
 pub use crate::parser::parser::Parser;
 pub use crate::output::output_generator::OutputGenerator;
 pub use crate::output::handlebars_output_generator::HandlebarsOutputGenerator;
@@ -31,6 +38,12 @@ pub fn load_parser_from_file(path: &str) -> Result<SimpleParser, SimpleError> {
     Ok(parser)
 }
 
+/// Read queries saved by name, written one per line as `name = query`, from a file
+pub fn load_saved_queries_from_file(path: &str) -> Result<SavedQueries, SimpleError> {
+    let data = try_with!(fs::read_to_string(path), "Unable to read saved queries");
+    data.parse()
+}
+
 /// Create output generator from a template file
 pub fn load_output_generator_from_file(path: &str) -> Result<Box<dyn OutputGenerator>, SimpleError> {
     Ok(try_with!(HandlebarsOutputGenerator::from_file(path), "Unable to construct handlebars output generator"))
@@ -92,6 +105,331 @@ mod tests {
         assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
     }
     
+    #[test]
+    fn test_process_query_on_log_line_comparison() {
+        let parser = toy_parser();
+        let parsed_log = match parser.parse(&sample_log()) {
+            Ok(log) => log,
+            Err(err) => panic!(err),
+        };
+        let query = Query::new("millisecond>=\"37\"&&second<\"100\"&&verbosity!=\"DEBUG\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("millisecond>\"37\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_regex() {
+        let parser = toy_parser();
+        let parsed_log = match parser.parse(&sample_log()) {
+            Ok(log) => log,
+            Err(err) => panic!(err),
+        };
+        let query = Query::new("content~\"Snapshotting.*enabled\"&&class!~\"^Image$\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        assert!(Query::new("content~\"(unclosed\"").is_err());
+        assert!(Query::new("class~\"(\" && class in ()").is_err(), "Constraints are checked even if they are folded away");
+        assert!(Query::new("x~\"(\" || !x~\"(\"").is_err());
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_negated_group() {
+        let parser = toy_parser();
+        let parsed_log = match parser.parse(&sample_log()) {
+            Ok(log) => log,
+            Err(err) => panic!(err),
+        };
+        let query = Query::new("!(class=\"A\" || class=\"B\") && verbosity=\"INFO\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("!(class=\"ImageManagerImpl\" || class=\"B\") && verbosity=\"INFO\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_containment() {
+        let parser = toy_parser();
+        let parsed_log = match parser.parse(&sample_log()) {
+            Ok(log) => log,
+            Err(err) => panic!(err),
+        };
+        let query = Query::new("content contains \"Snapshotting\" && thread startswith \"ImageManager\" && class iendswith \"IMPL\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("content contains \"timeout\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_glob_and_case_insensitive_equality() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::new("thread=*\"*-dispatcher\" && class=i\"imagemanagerimpl\"")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("thread=*\"imagemanager*\"")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false, "Globs are case sensitive");
+        let query = Query::new("verbosity =i info")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("verbosity=info")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_field_comparison() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::new("thread startswith field(class) && thread != field(class) && client_id < field( verbosity )")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let resolved = Query::new("class = field(thread) || class = field(user)")?.resolve_fields(&parser);
+        assert_eq!(process_query_on_log_line(&resolved, &*parsed_log), false);
+        assert!(Query::new("class ~ field(thread)").is_err(), "Patterns have to be literals");
+        assert!(Query::new("@level >= field(verbosity)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_set_membership() {
+        let parser = toy_parser();
+        let parsed_log = match parser.parse(&sample_log()) {
+            Ok(log) => log,
+            Err(err) => panic!(err),
+        };
+        let query = Query::new("class in (\"A\", \"ImageManagerImpl\") && verbosity not in (\"DEBUG\", \"TRACE\")").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("class in (\"A\", \"B\")").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_existence() {
+        let parser = SimpleParser::new("optional_parser", r"(?P<verbosity>\w+)( \[(?P<client_id>\w+)\])?").unwrap();
+        let with_client = parser.parse("INFO [abc123]").unwrap();
+        let without_client = parser.parse("INFO").unwrap();
+        let query = Query::new("exists(client_id)").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*with_client), true);
+        assert_eq!(process_query_on_log_line(&query, &*without_client), false);
+        let query = Query::new("missing(client_id) && verbosity=\"INFO\"").unwrap();
+        assert_eq!(process_query_on_log_line(&query, &*with_client), false);
+        assert_eq!(process_query_on_log_line(&query, &*without_client), true);
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_time_range() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::new("@time >= \"2020/07/17 23:00:00\" && @time < \"2020/07/17 23:30\"")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("class=\"ImageManagerImpl\"")?.since("2020/07/17 23:12:31".parse()?);
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        let query = Query::new("class=\"ImageManagerImpl\"")?.since("2020/07/17".parse()?).until("2020/07/18".parse()?);
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        assert!(Query::new("@time >= \"yesterday\"").is_err());
+        assert!(Query::new("@time contains \"2020\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_level() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::new("@level >= DEBUG && @level < WARN")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("class=\"ImageManagerImpl\"")?.min_level(Verbosity::Warn);
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        assert!(Query::new("@level >= LOUD").is_err());
+        let parser = SimpleParser::new("level_parser", r"(?P<level>\w+) (?P<content>.*)")?;
+        let parsed_log = parser.parse("INFO done")?;
+        let query = Query::new("level contains \"INF\" && level =i info && @level >= INFO")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true, "A captured level field is compared as text");
+        assert!(Query::new("level >= WARN").is_err(), "Level names are only ordered by severity with @level");
+        assert!(Query::new("verbosity < \"error\"").is_err());
+        assert!(Query::new("level >= 3 && verbosity <= field(level)").is_ok(), "Other values are still compared as numbers or text");
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_resolved_query_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let raw_queries = [
+            ("verbosity=\"INFO\" && class=\"ImageManagerImpl\"", true),
+            ("verbosity=\"INFO\" && !(class=\"ImageManagerImpl\" || thread in (\"main\"))", false),
+            ("missing(user) && exists(thread)", true),
+            ("user=\"root\"", false),
+        ];
+        for (raw_query, expected) in raw_queries.iter() {
+            let query = Query::new(raw_query)?;
+            assert_eq!(process_query_on_log_line(&query, &*parsed_log), *expected, "Checking {} by name", raw_query);
+            let query = Query::new(raw_query)?.resolve_fields(&parser);
+            assert_eq!(process_query_on_log_line(&query, &*parsed_log), *expected, "Checking {} by slot", raw_query);
+        }
+        let other_parser = SimpleParser::new("other_parser", r"(?P<user>\w+) (?P<class>\w+)")?;
+        let query = Query::new("class=\"ImageManagerImpl\"")?.resolve_fields(&parser);
+        assert_eq!(process_query_on_log_line(&query, &*other_parser.parse("root ImageManagerImpl")?), true, "Lines of other parsers are checked by name");
+        assert_eq!(process_query_on_log_line(&query, &*other_parser.parse("ImageManagerImpl root")?), false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_pipeline() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let query = Query::new("verbosity=\"INFO\" | sort -@time | head 2 | fields class")?.resolve_fields(&parser);
+        let mut pipeline = query.pipeline();
+        let mut emitted = Vec::new();
+        for (time, verbosity, class) in [("23:12", "INFO", "A"), ("23:40", "INFO", "B"), ("23:50", "WARN", "C"), ("23:30", "INFO", "D")].iter() {
+            let log = sample_log().replacen("23:12", time, 1).replacen("INFO", verbosity, 1).replace("ImageManagerImpl]", &format!("{}]", class));
+            let parsed_log = parser.parse(&log)?;
+            if process_query_on_log_line(&query, &*parsed_log) {
+                pipeline.push(parsed_log, &mut |record| emitted.push(record));
+            }
+        }
+        pipeline.finish(&mut |record| emitted.push(record));
+        let emitted: Vec<_> = emitted.iter().map(|record| record.get_content().clone()).collect();
+        let row = |class: &str| [(String::from("class"), String::from(class))].iter().cloned().collect::<std::collections::HashMap<String, String>>();
+        assert_eq!(emitted, vec![row("B"), row("D")]);
+
+        assert!(Query::new("class=A | timechart span=1m count(), count() by verbosity").is_err(), "Columns of a timechart have to be distinct");
+        assert!(Query::new("class=A | timechart span=1m max(thread) as @time").is_err());
+        assert!(Query::new("class=A | timechart span=1m count(), count() as total by verbosity").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_quoted_strings_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let cases = [
+            ("content contains 'Process snapshot' && class = 'ImageManagerImpl'", true),
+            ("content startswith \"\\u{50}rocess\" && 'not enabled'", true),
+            ("content ~ \"snapshot:\\\\s\"", true),
+            ("content contains 'Process snapshot\\n'", false),
+        ];
+        for (raw, expected) in cases.iter() {
+            let query = Query::new(raw)?;
+            assert_eq!(process_query_on_log_line(&query, &*parsed_log), *expected, "Checking {}", raw);
+        }
+        assert!(Query::new("content ~ \"snapshot:\\s\"").is_err(), "Backslashes of regular expressions are escaped too");
+        assert_eq!(Query::new("content contains 'say \"hi\"\\t'")?.to_string(), "content contains \"say \\\"hi\\\"\\t\"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_from_json() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::from_json(r#"{
+                "tree": {"Binary": [
+                    {"Unary": {"query_key": "class", "query_constraint": "EQ", "query_value": {"Literal": "ImageManagerImpl"}}},
+                    "OR",
+                    {"Unary": {"Not": {"Unary": {"query_key": "content", "query_constraint": "CONTAINS", "query_value": {"Literal": "say \"hi\""}}}}}
+                ]},
+                "stages": [{"Head": 1}]
+            }"#)?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        assert_eq!(query.to_string(), "class = \"ImageManagerImpl\" || !content contains \"say \\\"hi\\\"\" | head 1", "Values are escaped when written");
+        assert_eq!(Query::new(&query.to_string())?.to_string(), query.to_string(), "The written query parses back");
+        let invalid = [
+            r#"{"tree": {"Unary": {"Unary": {"query_key": "class", "query_constraint": "EQ", "query_value": {"List": ["A"]}}}}}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Timechart": {"span": {"amount": 0, "unit": "Minute"}, "aggregates": [], "by": null}}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Timechart": {"span": {"amount": 99999999999999999, "unit": "Day"}, "aggregates": [], "by": null}}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Stats": {"aggregates": [{"function": "Avg", "field": null, "alias": null}], "by": []}}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Eval": [{"field": "x", "expression": {"Call": ["Substr", [{"Field": "class"}]]}}]}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Eval": [{"field": "x", "expression": {"Number": "one"}}]}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Head": -1}]}"#,
+            r#"{"tree": {"Unary": {"Unary": {"query_key": "", "query_constraint": "EQ", "query_value": {"Literal": "A"}}}}}"#,
+            r#"{"tree": {"Unary": {"Unary": {"query_key": "a || b", "query_constraint": "EQ", "query_value": {"Literal": "A"}}}}}"#,
+            r#"{"tree": {"Unary": {"Unary": {"query_key": "a", "query_constraint": "EQ", "query_value": {"Field": "b c"}}}}}"#,
+            r#"{"tree": {"Unary": {"Not": {"Reference": "x y"}}}}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Sort": [{"key": "", "descending": false}]}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Stats": {"aggregates": [{"function": "Count", "field": null, "alias": "n m"}], "by": []}}]}"#,
+            r#"{"tree": {"Unary": {"FullText": "A"}}, "stages": [{"Eval": [{"field": "x", "expression": {"Field": "a|b"}}]}]}"#,
+        ];
+        for json in invalid.iter() {
+            assert!(Query::from_json(json).is_err(), "{} is not a valid query", json);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_built_query_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::field("class").eq("ImageManagerImpl").and(Query::field("@level").gte("INFO")).build()?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = (!Query::text("liquid")).or(Query::field("thread").is_in(&["main"])).build()?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_with_functions_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let functions = QueryFunctions::new()
+            .register("is_impl", |log_line, arguments| log_line.get_field(&arguments[0]).is_some_and(|value| value.ends_with("Impl")))
+            .register("after", |log_line, arguments| log_line.get_timestamp().is_some_and(|timestamp| *timestamp >= arguments[1].parse().unwrap()));
+        let query = Query::with_functions("is_impl(class) && after(@time, \"2020/07/17 23:00\")", &functions)?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::with_functions("is_impl(thread) || !after(@time, \"2020/07/17\")", &functions)?.resolve_fields(&parser);
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        let query = Query::call("is_impl", &["application"]).build_with_functions(&functions)?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        assert!(Query::with_functions("is_internal_ip(client)", &functions).is_err(), "Functions have to be registered");
+        assert!(Query::new("is_impl(class)").is_err());
+        assert!(Query::with_functions("class in () && is_internal_ip(client)", &functions).is_err(), "Calls are checked even if they are folded away");
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_with_parameters_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let parameters = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect::<std::collections::HashMap<String, String>>();
+        let raw = "class = $class && @time >= $since";
+        let query = Query::with_parameters(raw, &parameters(&[("class", "ImageManagerImpl"), ("since", "2020/07/17 23:00")]))?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        assert_eq!(query.to_string(), "class = \"ImageManagerImpl\" && @time >= \"2020/07/17 23:00\"");
+        let query = Query::with_parameters(raw, &parameters(&[("class", "x\" || class = \"ImageManagerImpl"), ("since", "2020/07/17")]))?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false, "Quotes in a value cannot end it");
+        assert_eq!(query.to_string(), "class = \"x\\\" || class = \\\"ImageManagerImpl\" && @time >= \"2020/07/17\"", "Bound values are escaped when written");
+        assert_eq!(Query::new(&query.to_string())?.to_string(), query.to_string(), "The written query parses back to the same query");
+        assert_eq!(process_query_on_log_line(&Query::new(&query.to_string())?, &*parsed_log), false);
+        assert!(Query::with_parameters(raw, &parameters(&[("class", "A")])).is_err(), "Every placeholder needs a value");
+        assert!(Query::new("class = $class").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_saved_query_on_log_line() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let saved_queries: SavedQueries = "
+            info_in_dispatcher = verbosity=\"INFO\" && thread=\"ImageManagerImpl-dispatcher\"
+            by_client = client_id=$client
+        ".parse()?;
+        let parameters = [(String::from("client"), String::from(""))].iter().cloned().collect();
+        let context = QueryContext::new().saved_queries(saved_queries).parameters(parameters);
+        let query = Query::with_context("@info_in_dispatcher", &context)?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::with_context("@info_in_dispatcher && !@by_client", &context)?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        assert_eq!(query.to_string(), "(verbosity = \"INFO\" && thread = \"ImageManagerImpl-dispatcher\") && !(client_id = \"\")");
+        assert!(Query::new("@info_in_dispatcher").is_err(), "Saved queries have to be given");
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_query_on_log_line_full_text() -> Result<(), SimpleError> {
+        let parser = toy_parser();
+        let parsed_log = parser.parse(&sample_log())?;
+        let query = Query::new("\"Snapshotting not enabled\" && verbosity=\"INFO\"")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), true);
+        let query = Query::new("\"NullPointerException\" || !\"liquid-server\"")?;
+        assert_eq!(process_query_on_log_line(&query, &*parsed_log), false);
+        Ok(())
+    }
+
     #[test]
     fn test_java_parse() -> Result<(), SimpleError> {
         let log_line = sample_log();
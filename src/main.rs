@@ -4,6 +4,7 @@ use std::io::BufReader;
 use std::io::{Error, prelude::*};
 use std::path::PathBuf;
 use std::fs::File;
+use std::process;
 use log_query::*;
 use log_query::Parser;
 use log_query::OutputGenerator;
@@ -27,6 +28,26 @@ struct Args {
     #[structopt(long = "json", conflicts_with="handlebars", required_unless="handlebars")]
     json: bool,
 
+    /// Only include log lines written at or after this time, e.g. "2020/07/17 23:00:00"
+    #[structopt(long = "since")]
+    since: Option<Timestamp>,
+
+    /// Only include log lines written before this time
+    #[structopt(long = "until")]
+    until: Option<Timestamp>,
+
+    /// Only include log lines at least as severe as this verbosity, e.g. WARN
+    #[structopt(long = "min-level")]
+    min_level: Option<Verbosity>,
+
+    /// Value for a placeholder of the query, e.g. client=abc123 for $client
+    #[structopt(long = "param", parse(try_from_str = parse_parameter), number_of_values = 1)]
+    parameters: Vec<(String, String)>,
+
+    /// File of queries saved by name, one per line as `name = query`, which the query can refer to as @name
+    #[structopt(long = "queries")]
+    saved_queries_path: Option<PathBuf>,
+
     /// File to parse
     file: PathBuf,
 
@@ -34,6 +55,14 @@ struct Args {
     query: String,
 }
 
+/// Split a parameter given as `name=value`
+fn parse_parameter(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((name, value)) => Ok((String::from(name), String::from(value))),
+        None => Err(format!("Expected a parameter as name=value, got {}", raw)),
+    }
+}
+
 fn main() -> Result<(), Error> {
     let args: Args = Args::from_args();
     let parser_profile_path = args.parser_profile_path.as_path().to_str().unwrap();
@@ -47,7 +76,32 @@ fn main() -> Result<(), Error> {
         panic!("No output format specified")
     };
 
-    let query = Query::new(&args.query).unwrap();
+    let mut context = QueryContext::new().parameters(args.parameters.into_iter().collect());
+    if let Some(saved_queries_path) = args.saved_queries_path {
+        context = match load_saved_queries_from_file(saved_queries_path.as_path().to_str().unwrap()) {
+            Ok(saved_queries) => context.saved_queries(saved_queries),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        };
+    }
+    let mut query = match Query::with_context(&args.query, &context) {
+        Ok(query) => query.resolve_fields(&parser),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(since) = args.since {
+        query = query.since(since);
+    }
+    if let Some(until) = args.until {
+        query = query.until(until);
+    }
+    if let Some(min_level) = args.min_level {
+        query = query.min_level(min_level);
+    }
 
     let file_path = args.file.as_path().to_str().unwrap();
     let file = File::open(file_path)?;
@@ -55,15 +109,21 @@ fn main() -> Result<(), Error> {
     // let output_generator = load_output_generator_from_file(path: &str)
 
     let reader = BufReader::new(file);
+    let mut pipeline = query.pipeline();
+    let mut emit = |record: Record| println!("{}", output_generator.get_str(&*record));
     for line in reader.lines() {
         if let Ok(line) = line { 
             if let Ok(result) = parser.parse(&line){
                 if process_query_on_log_line(&query, result.as_ref()) {
-                    println!("{}", output_generator.get_str(&*result));
+                    pipeline.push(result, &mut emit);
+                    if pipeline.is_done() {
+                        break;
+                    }
                 }
             }
         }
     }
+    pipeline.finish(&mut emit);
 
     Ok(())
 }
@@ -1,27 +1,91 @@
+use std::cell::OnceCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 use crate::parser::log_line_parse_result::LogLineParseResult;
+use crate::parser::timestamp::Timestamp;
+use crate::parser::verbosity::Verbosity;
+
+/// Captures that the verbosity of a log line is read from, in order of preference
+pub const VERBOSITY_FIELDS: [&str; 2] = ["verbosity", "level"];
 
 #[derive(Debug)]
 pub struct DefaultLogLineParseResult {
-    pub content: HashMap<String, String>,
+    /// Names of the fields, shared by every line from the same parser
+    pub field_names: Rc<[String]>,
+    /// Captured values in the same order as `field_names`
+    pub values: Vec<Option<String>>,
+    pub timestamp: Option<Timestamp>,
+    pub verbosity: Option<Verbosity>,
+    /// Fields by name, only built when asked for since most lines are filtered out before that
+    content: OnceCell<HashMap<String, String>>,
 }
 
 /// The result of parsing a log line
 impl DefaultLogLineParseResult {
     pub fn new(content: HashMap<String, String>) -> Self {
-        DefaultLogLineParseResult {
-            content,
-        }
+        Self::with_timestamp(content, None)
+    }
+
+    /// Create a parse result for a log line written at a known time
+    pub fn with_timestamp(content: HashMap<String, String>, timestamp: Option<Timestamp>) -> Self {
+        let (field_names, values): (Vec<String>, Vec<Option<String>>) = content.iter()
+            .map(|(name, value)| (name.clone(), Some(value.clone())))
+            .unzip();
+        let mut result = Self::from_slots(field_names.into(), values, timestamp);
+        result.content = OnceCell::from(content);
+        result
+    }
+
+    /// Create a parse result from values in the slot order given by `field_names`
+    pub fn from_slots(field_names: Rc<[String]>, values: Vec<Option<String>>, timestamp: Option<Timestamp>) -> Self {
+        let mut result = DefaultLogLineParseResult {
+            field_names,
+            values,
+            timestamp,
+            verbosity: None,
+            content: OnceCell::new(),
+        };
+        result.verbosity = VERBOSITY_FIELDS.iter()
+            .filter_map(|field| result.get_field(field))
+            .find_map(|value| value.parse().ok());
+        result
     }
 }
 
 impl LogLineParseResult for DefaultLogLineParseResult {
     fn get_content(&self) -> &HashMap<String, String> {
-        &self.content
+        self.content.get_or_init(|| {
+            self.field_names.iter()
+                .zip(self.values.iter())
+                .filter_map(|(name, value)| value.as_ref().map(|value| (name.clone(), value.clone())))
+                .collect()
+        })
     }
 
     fn get_field(&self, field: &str) -> Option<&String> {
-        self.content.get(field)
+        // a linear scan beats hashing for the handful of captures a profile has
+        let slot = self.field_names.iter().position(|name| name == field)?;
+        self.get_slot(slot)
+    }
+
+    fn get_field_names(&self) -> Option<&Rc<[String]>> {
+        Some(&self.field_names)
+    }
+
+    fn get_slots(&self) -> Option<&[Option<String>]> {
+        Some(&self.values)
+    }
+
+    fn get_values(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        Box::new(self.values.iter().flatten())
+    }
+
+    fn get_timestamp(&self) -> Option<&Timestamp> {
+        self.timestamp.as_ref()
+    }
+
+    fn get_verbosity(&self) -> Option<Verbosity> {
+        self.verbosity
     }
 }
 
@@ -38,5 +102,39 @@ mod tests {
                 .collect::<HashMap<String, String>>(),
         );
         assert_eq!(*log_line.get_field("test_field").unwrap(), "test_value");
+        assert_eq!(log_line.get_verbosity(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_default_log_line_verbosity() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("verbosity"), String::from("WARN"))]
+                .iter()
+                .cloned()
+                .collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(log_line.get_verbosity(), Some(Verbosity::Warn));
+    }
+
+    #[test]
+    fn test_default_log_line_slots() {
+        let log_line = DefaultLogLineParseResult::from_slots(
+            vec![String::from("verbosity"), String::from("user"), String::from("class")].into(),
+            vec![Some(String::from("ERROR")), None, Some(String::from("Class1"))],
+            None,
+        );
+        assert_eq!(*log_line.get_slot(2).unwrap(), "Class1");
+        assert_eq!(log_line.get_slot(1), None, "Slots that did not capture are empty");
+        assert_eq!(log_line.get_slot(3), None);
+        assert_eq!(*log_line.get_field("class").unwrap(), "Class1");
+        assert_eq!(log_line.get_field("user"), None);
+        assert_eq!(log_line.get_verbosity(), Some(Verbosity::Error));
+        assert_eq!(
+            *log_line.get_content(),
+            [(String::from("verbosity"), String::from("ERROR")), (String::from("class"), String::from("Class1"))]
+                .iter()
+                .cloned()
+                .collect::<HashMap<String, String>>()
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use std::collections::HashMap;
+use std::rc::Rc;
+use crate::parser::timestamp::Timestamp;
+use crate::parser::verbosity::Verbosity;
 
 /// Stores the result of parsing a log line
 pub trait LogLineParseResult {
@@ -7,4 +10,37 @@ pub trait LogLineParseResult {
 
     /// Gets the value of specific parsed field for this log line
     fn get_field(&self, field: &str) -> Option<&String>;
+
+    /// Names of the fields in slot order, shared by every log line of the parser that produced it
+    ///
+    /// Log lines that do not store their fields by slot have none.
+    fn get_field_names(&self) -> Option<&Rc<[String]>> {
+        None
+    }
+
+    /// Gets the values of the fields in the order of `get_field_names`, where fields that were
+    /// not captured are empty
+    fn get_slots(&self) -> Option<&[Option<String>]> {
+        None
+    }
+
+    /// Gets the value of a field by its position in `get_field_names`
+    fn get_slot(&self, slot: usize) -> Option<&String> {
+        self.get_slots()?.get(slot)?.as_ref()
+    }
+
+    /// Gets the values of all captured fields, in no particular order
+    fn get_values(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        Box::new(self.get_content().values())
+    }
+
+    /// Gets the time this log line was written at, if the parser could determine it
+    fn get_timestamp(&self) -> Option<&Timestamp> {
+        None
+    }
+
+    /// Gets the severity of this log line, if it captured a known verbosity level
+    fn get_verbosity(&self) -> Option<Verbosity> {
+        self.get_field("verbosity")?.parse().ok()
+    }
 }
\ No newline at end of file
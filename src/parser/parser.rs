@@ -1,4 +1,5 @@
 use simple_error::{SimpleError};
+use std::rc::Rc;
 
 /// A simple interface for a entity that can parse a log line and produce a result of generic type.
 pub trait Parser {
@@ -9,4 +10,11 @@ pub trait Parser {
 
     /// The canonical name of this parser. 
     fn get_name(&self) -> &str;
+
+    /// Names of the fields this parser captures, in the slot order of its results
+    ///
+    /// Queries can only resolve their fields to slots for parsers that have them.
+    fn get_field_names(&self) -> Option<Rc<[String]>> {
+        None
+    }
 }
\ No newline at end of file
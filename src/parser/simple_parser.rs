@@ -5,21 +5,33 @@ use simple_error::{require_with, try_with, SimpleError};
 use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
 use crate::parser::{parser_profile, parser::Parser};
 use crate::parser::parser_profile::ParserProfile;
+use crate::parser::timestamp::Timestamp;
 
-use std::collections::HashMap;
+use std::rc::Rc;
 
 
 pub struct SimpleParser {
     profile: parser_profile::ParserProfile,
     compiled_line_regex: regex::Regex,
+    /// Names of the capture groups, which are the fields of every parsed line
+    field_names: Rc<[String]>,
+    /// Index of the capture group for each field
+    field_groups: Vec<usize>,
 }
 
 impl SimpleParser {
     /// Create a new parser with the given settings
     pub fn new(profile_name: &str, regex_str: &str) -> Result<Self, SimpleError> {
+        let compiled_line_regex = try_with!(Regex::new(regex_str), "Unable to compile provided spec");
+        let (field_groups, field_names): (Vec<usize>, Vec<String>) = compiled_line_regex.capture_names()
+            .enumerate()
+            .filter_map(|(group, name)| name.map(|name| (group, String::from(name))))
+            .unzip();
         Ok(SimpleParser{
             profile: ParserProfile::new(profile_name, regex_str),
-            compiled_line_regex: try_with!(Regex::new(regex_str), "Unable to compile provided spec"),
+            compiled_line_regex,
+            field_names: field_names.into(),
+            field_groups,
         })
     }
 
@@ -32,23 +44,25 @@ impl<'a> Parser for SimpleParser {
     type ParserResult = DefaultLogLineParseResult;
 
     /// Parse a single log line and marshall data into a struct
+    ///
+    /// If the profile captures year, month, day, hour, minute and second (and optionally
+    /// millisecond) groups, they are combined into the timestamp of the line.
     fn parse(&self, log: &str) -> Result<Box<DefaultLogLineParseResult>, SimpleError> {
         let matches: Captures = require_with!(self.compiled_line_regex.captures(log), "Unable to parse log line");
-        let matches: HashMap<String, String> = matches.iter()
-            .zip(self.compiled_line_regex.capture_names())
-            .filter_map(|(x, y)| match (x,y) {
-                (Some(x), Some(y)) => Some((x,y)),
-                _ => None,
-            } ) // only take matches where the value is captured
-            .map(|(capture, name)| (capture.as_str(), name))
-            .map(|(capture, name)| (String::from(name), String::from(capture)))
+        let timestamp = Timestamp::from_match(&matches);
+        let values = self.field_groups.iter()
+            .map(|group| matches.get(*group).map(|capture| String::from(capture.as_str())))
             .collect();
-        Ok(Box::new(DefaultLogLineParseResult::new(matches)))
+        Ok(Box::new(DefaultLogLineParseResult::from_slots(self.field_names.clone(), values, timestamp)))
     }
 
     fn get_name(&self) -> &str {
         self.profile.get_name()
     }
+
+    fn get_field_names(&self) -> Option<Rc<[String]>> {
+        Some(self.field_names.clone())
+    }
 }
 
 #[cfg(test)]
@@ -61,6 +75,29 @@ mod tests {
         let parser = SimpleParser::new("simple_parser", r"(?P<test_capture_group>test_[a-z]+)").unwrap();
         let log = "test_key";
         assert_eq!(parser.parse(log)?.get_field("test_capture_group").unwrap(), "test_key");
+        assert_eq!(parser.parse(log)?.get_timestamp(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_slots() -> Result<(), SimpleError> {
+        let parser = SimpleParser::new("slot_parser", r"(?P<key>[a-z]+)=(?P<value>\d+)?(x)?(?P<suffix>!)?")?;
+        assert_eq!(parser.get_field_names().unwrap()[..], ["key", "value", "suffix"]);
+        let parsed = parser.parse("a=!")?;
+        assert_eq!(*parsed.get_slot(0).unwrap(), "a");
+        assert_eq!(parsed.get_slot(1), None, "Optional groups that did not participate are empty");
+        assert_eq!(*parsed.get_slot(2).unwrap(), "!", "Unnamed groups do not take up a slot");
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_parse() -> Result<(), SimpleError> {
+        let parser = SimpleParser::new(
+            "timestamp_parser",
+            r"(?P<year>\d{4})/(?P<month>\d{2})/(?P<day>\d{2})\s(?P<hour>\d{2}):(?P<minute>\d{2}):(?P<second>\d{2})\.(?P<millisecond>\d{0,3})",
+        )?;
+        let log = "2020/07/17 23:12:30.037";
+        assert_eq!(*parser.parse(log)?.get_timestamp().unwrap(), log.parse::<Timestamp>()?);
         Ok(())
     }
 }
\ No newline at end of file
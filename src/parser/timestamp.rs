@@ -1,4 +1,38 @@
-#[derive(Clone, Debug, Eq, PartialEq)]
+use lazy_static::lazy_static;
+use regex::Regex;
+use simple_error::{require_with, try_with, SimpleError};
+use std::fmt;
+use std::str::FromStr;
+
+const MILLISECONDS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Days since 1970/01/01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // count years from March, so that the leap day is the last day of a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of a number of days since 1970/01/01, as year, month and day
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Point in time a log line was written at, ordered chronologically
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub struct Timestamp {
     year: u32,
     month: u32,
@@ -6,39 +40,158 @@ pub struct Timestamp {
     hour: u32,
     minute: u32,
     second: u32,
-    millisecond: Option<u32>,
+    millisecond: u32,
 }
 
 impl Timestamp {
-    pub fn new(year: &str, month: &str, day: &str, hour: &str, minute: &str, second: &str, millisecond: Option<&str>) -> Self {
-        Timestamp {
-            year: year.parse::<u32>().unwrap(),
-            month: month.parse::<u32>().unwrap(),
-            day: day.parse::<u32>().unwrap(),
-            hour: hour.parse::<u32>().unwrap(),
-            minute: minute.parse::<u32>().unwrap(),
-            second: second.parse::<u32>().unwrap(),
+    /// Create a timestamp from its components as written
+    ///
+    /// `millisecond` is the fraction of the second after the decimal point, so `5` is half a
+    /// second and `037` is 37 milliseconds. It defaults to zero when it is missing or empty.
+    pub fn new(year: &str, month: &str, day: &str, hour: &str, minute: &str, second: &str, millisecond: Option<&str>) -> Result<Self, SimpleError> {
+        Ok(Timestamp {
+            year: try_with!(year.parse::<u32>(), "Invalid year {}", year),
+            month: try_with!(month.parse::<u32>(), "Invalid month {}", month),
+            day: try_with!(day.parse::<u32>(), "Invalid day {}", day),
+            hour: try_with!(hour.parse::<u32>(), "Invalid hour {}", hour),
+            minute: try_with!(minute.parse::<u32>(), "Invalid minute {}", minute),
+            second: try_with!(second.parse::<u32>(), "Invalid second {}", second),
             millisecond: match millisecond {
-                Some(ms) => ms.parse::<u32>().ok(), 
-                None => None, 
+                Some(fraction) if !fraction.is_empty() => {
+                    let digits: String = fraction.chars().chain("00".chars()).take(3).collect();
+                    try_with!(digits.parse::<u32>(), "Invalid fraction of a second {}", fraction)
+                },
+                _ => 0,
             },
-        }
+        })
     }
 
-    // Convert a match object in the form regex::Captures into a Timestamp
-    pub fn from_match(captures: &regex::Captures) -> Self {
+    // Convert a match object in the form regex::Captures into a Timestamp, if it captured every component
+    pub fn from_match(captures: &regex::Captures) -> Option<Self> {
         Timestamp::new(
-            &captures["year"],
-            &captures["month"],
-            &captures["day"],
-            &captures["hour"],
-            &captures["minute"],
-            &captures["second"],
+            captures.name("year")?.as_str(),
+            captures.name("month")?.as_str(),
+            captures.name("day")?.as_str(),
+            captures.name("hour")?.as_str(),
+            captures.name("minute")?.as_str(),
+            captures.name("second")?.as_str(),
             match captures.name("millisecond") {
                 Some(ms) => Some(ms.as_str()),
                 None => None,
             },
+        ).ok()
+    }
+
+    /// Milliseconds since 1970/01/01 00:00:00, treating the timestamp as UTC
+    pub fn milliseconds(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = (self.hour as i64 * 60 + self.minute as i64) * 60 + self.second as i64;
+        days * MILLISECONDS_PER_DAY + seconds * 1000 + self.millisecond as i64
+    }
+
+    /// Inverse of `milliseconds`
+    pub fn from_milliseconds(milliseconds: i64) -> Self {
+        let (year, month, day) = civil_from_days(milliseconds.div_euclid(MILLISECONDS_PER_DAY));
+        let time = milliseconds.rem_euclid(MILLISECONDS_PER_DAY);
+        let seconds = time / 1000;
+        Timestamp {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            hour: (seconds / 3600) as u32,
+            minute: (seconds / 60 % 60) as u32,
+            second: (seconds % 60) as u32,
+            millisecond: (time % 1000) as u32,
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    /// Format like `2020/07/17 23:12:30.037`, which parses back into the same timestamp
+    ///
+    /// The milliseconds of whole seconds are left out.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}/{:02}/{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)?;
+        if self.millisecond > 0 {
+            write!(f, ".{:03}", self.millisecond)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = SimpleError;
+
+    /// Parse a timestamp like `2020/07/17 23:12:30.037`
+    ///
+    /// Dates may also be separated with `-` and times with `T`. Trailing time components
+    /// can be left out and default to zero.
+    fn from_str(s: &str) -> Result<Timestamp, SimpleError> {
+        lazy_static! {
+            static ref TIMESTAMP_FORMAT: Regex = Regex::new(
+                r"^(?P<year>\d{4})[/-](?P<month>\d{1,2})[/-](?P<day>\d{1,2})(?:[ T](?P<hour>\d{1,2}):(?P<minute>\d{2})(?::(?P<second>\d{2})(?:\.(?P<millisecond>\d{1,3}))?)?)?$"
+            ).unwrap();
+        }
+        let captures = require_with!(TIMESTAMP_FORMAT.captures(s.trim()), "Unable to parse timestamp {}", s);
+        let component = |name| captures.name(name).map_or("0", |m| m.as_str());
+        Timestamp::new(
+            component("year"),
+            component("month"),
+            component("day"),
+            component("hour"),
+            component("minute"),
+            component("second"),
+            captures.name("millisecond").map(|m| m.as_str()),
         )
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_from_str() -> Result<(), SimpleError> {
+        assert_eq!(
+            Timestamp::from_str("2020/07/17 23:12:30.037")?,
+            Timestamp::new("2020", "07", "17", "23", "12", "30", Some("037"))?
+        );
+        assert_eq!(
+            Timestamp::from_str("2020-07-17T23:12")?,
+            Timestamp::new("2020", "07", "17", "23", "12", "0", None)?
+        );
+        assert_eq!(
+            Timestamp::from_str("2020/07/17")?,
+            Timestamp::new("2020", "07", "17", "0", "0", "0", None)?
+        );
+        assert_eq!(
+            Timestamp::from_str("2020/07/17 23:12:30.5")?,
+            Timestamp::new("2020", "07", "17", "23", "12", "30", Some("500"))?,
+            "The fraction of a second is not a number of milliseconds"
+        );
+        assert!(Timestamp::from_str("yesterday").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_ordering() -> Result<(), SimpleError> {
+        assert!(Timestamp::from_str("2020/07/17 23:00:00")? < Timestamp::from_str("2020/07/17 23:12:30.037")?);
+        assert!(Timestamp::from_str("2020/07/18")? > Timestamp::from_str("2020/07/17 23:12:30.037")?);
+        assert_eq!(Timestamp::from_str("2020/07/17 23:12:30")?, Timestamp::from_str("2020/07/17 23:12:30.000")?);
+        assert!(Timestamp::from_str("2020/07/17 23:12:30.5")? > Timestamp::from_str("2020/07/17 23:12:30.010")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_milliseconds() -> Result<(), SimpleError> {
+        assert_eq!(Timestamp::from_str("1970/01/01")?.milliseconds(), 0);
+        assert_eq!(Timestamp::from_str("2020/07/17 23:12:30.037")?.milliseconds(), 1_595_027_550_037);
+        assert_eq!(Timestamp::from_str("1969/12/31 23:59:59")?.milliseconds(), -1000);
+        for raw in ["2020/07/17 23:12:30.037", "2020/02/29 00:00:00", "2000/12/31 23:59:59", "1901/03/01 12:00:00.500"].iter() {
+            let timestamp = Timestamp::from_str(raw)?;
+            assert_eq!(Timestamp::from_milliseconds(timestamp.milliseconds()), timestamp);
+            assert_eq!(timestamp.to_string(), *raw);
+        }
+        Ok(())
+    }
+}
@@ -3,24 +3,53 @@
 use simple_error::{bail, SimpleError};
 use std::str::FromStr;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Severity of a log line, ordered from least to most severe
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum Verbosity {
+    Trace,
     Debug,
     Info,
-    Error,
     Warn,
+    Error,
+    Fatal,
 }
 
 impl FromStr for Verbosity {
     type Err =  SimpleError;
 
+    /// Parse a verbosity level, ignoring case
     fn from_str(s: &str) -> Result<Verbosity, SimpleError> {
-        Ok(match s {
+        Ok(match s.to_uppercase().as_str() {
+            "TRACE" => Verbosity::Trace,
             "DEBUG" => Verbosity::Debug,
             "INFO" => Verbosity::Info,
+            "WARN" | "WARNING" => Verbosity::Warn,
             "ERROR" => Verbosity::Error,
-            "WARN" => Verbosity::Warn,
-            _ => bail!("Unknown verbosity"),
+            "FATAL" => Verbosity::Fatal,
+            _ => bail!("Unknown verbosity {}", s),
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbosity_from_str() -> Result<(), SimpleError> {
+        assert_eq!(Verbosity::from_str("WARN")?, Verbosity::Warn);
+        assert_eq!(Verbosity::from_str("warning")?, Verbosity::Warn);
+        assert_eq!(Verbosity::from_str("Fatal")?, Verbosity::Fatal);
+        assert!(Verbosity::from_str("LOUD").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbosity_ordering() {
+        assert!(Verbosity::Trace < Verbosity::Debug);
+        assert!(Verbosity::Debug < Verbosity::Info);
+        assert!(Verbosity::Info < Verbosity::Warn);
+        assert!(Verbosity::Warn < Verbosity::Error);
+        assert!(Verbosity::Error < Verbosity::Fatal);
+    }
+}
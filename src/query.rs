@@ -1,4 +1,15 @@
 pub mod simple_query;
 pub mod constraint;
+pub mod parse_error;
+pub mod pipeline;
+pub mod builder;
+pub mod functions;
+pub mod saved_queries;
+pub mod context;
 mod query_ast;
+mod canonical;
+mod optimizer;
+mod program;
+mod stats;
+mod eval;
 mod constraint_factory;
\ No newline at end of file
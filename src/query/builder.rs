@@ -0,0 +1,278 @@
+//! Building the filter of a query in Rust rather than writing it as text
+use crate::query::query_ast::{QueryAtom, QueryConstraint, QueryExpression, QueryOpExpression, QueryOpTerm, QueryTerm, QueryValue};
+use crate::query::functions::QueryFunctions;
+use crate::query::query_ast;
+use crate::query::simple_query::Query;
+use simple_error::SimpleError;
+use std::ops::Not;
+
+/// A filter under construction, started with `Query::field` or `Query::text`
+///
+/// Filters are combined with `and`, `or` and `!` into the parse tree the same filter
+/// would have when written as text, so `a.and(b).or(c)` is the same as `a && b || c`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryBuilder {
+    tree: QueryExpression<String>,
+}
+
+/// Turn an expression into a term, grouping it if it is a disjunction
+fn into_term(expression: QueryExpression<String>) -> QueryTerm<String> {
+    match expression {
+        QueryExpression::Unary(term) => term,
+        expression => QueryTerm::Group(Box::new(expression)),
+    }
+}
+
+/// Turn a term into a factor, grouping it if it is a conjunction
+fn into_factor(term: QueryTerm<String>) -> QueryTerm<String> {
+    match term {
+        QueryTerm::Binary(..) => QueryTerm::Group(Box::new(QueryExpression::Unary(term))),
+        term => term,
+    }
+}
+
+/// Join two terms with `&&`, keeping the chain nested to the right like the parser does
+fn and_terms(left: QueryTerm<String>, right: QueryTerm<String>) -> QueryTerm<String> {
+    match left {
+        QueryTerm::Binary(factor, op, term) => QueryTerm::Binary(factor, op, Box::new(and_terms(*term, right))),
+        left => QueryTerm::Binary(Box::new(left), QueryOpTerm::AND, Box::new(right)),
+    }
+}
+
+/// Join two expressions with `||`, keeping the chain nested to the right like the parser does
+fn or_expressions(left: QueryExpression<String>, right: QueryExpression<String>) -> QueryExpression<String> {
+    match left {
+        QueryExpression::Binary(term, op, expression) => QueryExpression::Binary(term, op, Box::new(or_expressions(*expression, right))),
+        QueryExpression::Unary(term) => QueryExpression::Binary(term, QueryOpExpression::OR, Box::new(right)),
+    }
+}
+
+impl QueryBuilder {
+    fn atom(key: &str, query_constraint: QueryConstraint, query_value: QueryValue<String>) -> Self {
+        QueryBuilder {
+            tree: QueryExpression::Unary(QueryTerm::Unary(QueryAtom { query_key: String::from(key), query_constraint, query_value })),
+        }
+    }
+
+    /// A full text search that passes when any captured field contains `value`
+    pub fn text(value: &str) -> Self {
+        QueryBuilder {
+            tree: QueryExpression::Unary(QueryTerm::FullText(String::from(value))),
+        }
+    }
+
+    /// A call of a function registered with the query, with its arguments as they would be written
+    pub fn call(name: &str, arguments: &[&str]) -> Self {
+        QueryBuilder {
+            tree: QueryExpression::Unary(QueryTerm::Call(String::from(name), arguments.iter().map(|argument| String::from(*argument)).collect())),
+        }
+    }
+
+    /// Require both this filter and `other` to pass, like `&&`
+    pub fn and(self, other: QueryBuilder) -> Self {
+        QueryBuilder {
+            tree: QueryExpression::Unary(and_terms(into_term(self.tree), into_term(other.tree))),
+        }
+    }
+
+    /// Require this filter or `other` to pass, like `||`
+    pub fn or(self, other: QueryBuilder) -> Self {
+        QueryBuilder {
+            tree: or_expressions(self.tree, other.tree),
+        }
+    }
+
+    /// Create the query, which fails like `Query::new` would, e.g. for an invalid regular expression
+    pub fn build(self) -> Result<Query, SimpleError> {
+        self.build_with_functions(&QueryFunctions::new())
+    }
+
+    /// Create the query, which can call the given functions like `Query::with_functions`
+    pub fn build_with_functions(self, functions: &QueryFunctions) -> Result<Query, SimpleError> {
+        Query::from_parse_tree(query_ast::Query { tree: self.tree, stages: Vec::new() }, functions)
+    }
+}
+
+impl Not for QueryBuilder {
+    type Output = QueryBuilder;
+
+    /// Require this filter to fail, like `!`
+    fn not(self) -> Self::Output {
+        QueryBuilder {
+            tree: QueryExpression::Unary(QueryTerm::Not(Box::new(into_factor(into_term(self.tree))))),
+        }
+    }
+}
+
+/// A key that a constraint is being built for, started with `Query::field`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldBuilder {
+    key: String,
+}
+
+impl FieldBuilder {
+    pub fn new(key: &str) -> Self {
+        FieldBuilder {
+            key: String::from(key),
+        }
+    }
+
+    fn literal(self, query_constraint: QueryConstraint, value: &str) -> QueryBuilder {
+        QueryBuilder::atom(&self.key, query_constraint, QueryValue::Literal(String::from(value)))
+    }
+
+    fn list(self, query_constraint: QueryConstraint, values: &[&str]) -> QueryBuilder {
+        QueryBuilder::atom(&self.key, query_constraint, QueryValue::List(values.iter().map(|value| String::from(*value)).collect()))
+    }
+
+    /// `key = value`
+    pub fn eq(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::EQ, value)
+    }
+
+    /// `key =i value`
+    pub fn eq_ignore_case(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::IEQ, value)
+    }
+
+    /// `key =* pattern`
+    pub fn glob(self, pattern: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::GLOB, pattern)
+    }
+
+    /// `key != value`
+    pub fn ne(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::NE, value)
+    }
+
+    /// `key < value`
+    pub fn lt(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::LT, value)
+    }
+
+    /// `key <= value`
+    pub fn lte(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::LE, value)
+    }
+
+    /// `key > value`
+    pub fn gt(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::GT, value)
+    }
+
+    /// `key >= value`
+    pub fn gte(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::GE, value)
+    }
+
+    /// `key ~ pattern`
+    pub fn matches(self, pattern: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::MATCH, pattern)
+    }
+
+    /// `key !~ pattern`
+    pub fn not_matches(self, pattern: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::NOTMATCH, pattern)
+    }
+
+    /// `key contains value`
+    pub fn contains(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::CONTAINS, value)
+    }
+
+    /// `key startswith value`
+    pub fn starts_with(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::STARTSWITH, value)
+    }
+
+    /// `key endswith value`
+    pub fn ends_with(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::ENDSWITH, value)
+    }
+
+    /// `key icontains value`
+    pub fn contains_ignore_case(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::ICONTAINS, value)
+    }
+
+    /// `key istartswith value`
+    pub fn starts_with_ignore_case(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::ISTARTSWITH, value)
+    }
+
+    /// `key iendswith value`
+    pub fn ends_with_ignore_case(self, value: &str) -> QueryBuilder {
+        self.literal(QueryConstraint::IENDSWITH, value)
+    }
+
+    /// `key in (value, ...)`
+    pub fn is_in(self, values: &[&str]) -> QueryBuilder {
+        self.list(QueryConstraint::IN, values)
+    }
+
+    /// `key not in (value, ...)`
+    pub fn not_in(self, values: &[&str]) -> QueryBuilder {
+        self.list(QueryConstraint::NOTIN, values)
+    }
+
+    /// `exists(key)`
+    pub fn exists(self) -> QueryBuilder {
+        QueryBuilder::atom(&self.key, QueryConstraint::EXISTS, QueryValue::Empty)
+    }
+
+    /// `missing(key)`
+    pub fn missing(self) -> QueryBuilder {
+        QueryBuilder::atom(&self.key, QueryConstraint::MISSING, QueryValue::Empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert that a built filter is the one written as text
+    fn assert_written_as(built: QueryBuilder, raw: &str) {
+        let parsed = query_ast::parse_query(raw).unwrap().map(&query_ast::unescape).to_string();
+        assert_eq!(built.build().unwrap().to_string(), parsed, "Building {}", raw);
+    }
+
+    #[test]
+    fn test_build_atoms() {
+        assert_written_as(Query::field("class").eq("A"), "class=\"A\"");
+        assert_written_as(Query::field("class").glob("*Impl"), "class =* \"*Impl\"");
+        assert_written_as(Query::field("@level").gte("WARN"), "@level>=WARN");
+        assert_written_as(Query::field("content").matches("a.*b"), "content ~ \"a.*b\"");
+        assert_written_as(Query::field("thread").starts_with_ignore_case("main"), "thread istartswith main");
+        assert_written_as(Query::field("class").is_in(&["A", "B"]), "class in (A, B)");
+        assert_written_as(Query::field("class").not_in(&[]), "class not in ()");
+        assert_written_as(Query::field("client_id").missing(), "missing(client_id)");
+        assert_written_as(Query::text("error"), "\"error\"");
+        assert!(Query::call("is_internal_ip", &["client"]).build().is_err(), "Functions have to be registered");
+    }
+
+    #[test]
+    fn test_build_connectives() {
+        let a = || Query::field("a").eq("1");
+        let b = || Query::field("b").eq("2");
+        let c = || Query::field("c").eq("3");
+        assert_written_as(a().and(b()).and(c()), "a=\"1\" && b=\"2\" && c=\"3\"");
+        assert_written_as(a().and(b().and(c())), "a=\"1\" && b=\"2\" && c=\"3\"");
+        assert_written_as(a().or(b()).or(c()), "a=\"1\" || b=\"2\" || c=\"3\"");
+        assert_written_as(a().and(b()).or(c()), "a=\"1\" && b=\"2\" || c=\"3\"");
+        assert_written_as(a().and(b().or(c())), "a=\"1\" && (b=\"2\" || c=\"3\")");
+        assert_written_as(a().or(b()).and(c()), "(a=\"1\" || b=\"2\") && c=\"3\"");
+        assert_written_as(!a().and(b()), "!(a=\"1\" && b=\"2\")");
+        assert_written_as(!!a(), "!!a=\"1\"");
+        assert_eq!(
+            a().and(b()).and(c()),
+            a().and(b().and(c())),
+            "Conjunctions nest like they are parsed regardless of how they were combined"
+        );
+    }
+
+    #[test]
+    fn test_build_fails_like_parsing() {
+        assert!(Query::field("content").matches("(").build().is_err());
+        assert!(Query::field("@time").gt("yesterday").build().is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Canonical text form of parse trees, which parses back into the same tree
+//!
+//! Values are always double quoted, keys and operators are separated by single spaces and groups
+//! are kept as they were written, so that equivalent ways of writing a query print the same.
+//! Arguments of function calls are only quoted when they could not be written as keys.
+//!
+//! Trees are expected to hold values with their escape sequences already replaced, see
+//! `query_ast::unescape`, and quoted values are escaped again when they are written.
+use crate::query::query_ast::{
+    is_key_char, Aggregate, EvalAssignment, EvalExpression, EvalOperator, Query, QueryAtom, QueryConstraint, QueryExpression, QueryStage,
+    QueryTerm, QueryValue, SortKey, Span,
+};
+use std::fmt;
+
+/// Key, value or other part of a query as written
+fn text<T: AsRef<[u8]>>(part: &T) -> String {
+    String::from_utf8_lossy(part.as_ref()).into_owned()
+}
+
+/// Value written as a double quoted string, escaping quotes, backslashes and control characters
+fn quoted<T: AsRef<[u8]>>(value: &T) -> String {
+    let mut quoted = String::from("\"");
+    for c in text(value).chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write items separated by `, `
+fn write_list<T, F>(f: &mut fmt::Formatter, items: &[T], write: F) -> fmt::Result
+    where F: Fn(&mut fmt::Formatter, &T) -> fmt::Result {
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            write!(f, ", ")?;
+        }
+        write(f, item)?;
+    }
+    Ok(())
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for QueryAtom<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = text(&self.query_key);
+        match (&self.query_constraint, &self.query_value) {
+            (QueryConstraint::EXISTS, _) | (QueryConstraint::MISSING, _) => write!(f, "{}({})", self.query_constraint.symbol(), key),
+            (_, QueryValue::Literal(value)) => write!(f, "{} {} {}", key, self.query_constraint.symbol(), quoted(value)),
+            (_, QueryValue::Field(other)) => write!(f, "{} {} field({})", key, self.query_constraint.symbol(), text(other)),
+            (_, QueryValue::Parameter(name)) => write!(f, "{} {} ${}", key, self.query_constraint.symbol(), text(name)),
+            (_, QueryValue::List(values)) => {
+                write!(f, "{} {} (", key, self.query_constraint.symbol())?;
+                write_list(f, values, |f, value| write!(f, "{}", quoted(value)))?;
+                write!(f, ")")
+            },
+            (_, QueryValue::Empty) => write!(f, "{} {}", key, self.query_constraint.symbol()),
+        }
+    }
+}
+
+/// Write a term where the grammar expects a factor, grouping it if it is not one
+fn write_factor<T: AsRef<[u8]>>(f: &mut fmt::Formatter, term: &QueryTerm<T>) -> fmt::Result {
+    match term {
+        QueryTerm::Binary(..) => write!(f, "({})", term),
+        _ => write!(f, "{}", term),
+    }
+}
+
+/// Write an argument of a function call, quoting it unless it could be a key
+fn write_argument<T: AsRef<[u8]>>(f: &mut fmt::Formatter, argument: &T) -> fmt::Result {
+    let bytes = argument.as_ref();
+    if !bytes.is_empty() && bytes.iter().all(|c| is_key_char(*c)) {
+        write!(f, "{}", text(argument))
+    } else {
+        write!(f, "{}", quoted(argument))
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for QueryTerm<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryTerm::Unary(atom) => write!(f, "{}", atom),
+            QueryTerm::Binary(factor, _, term) => {
+                write_factor(f, factor)?;
+                write!(f, " && {}", term)
+            },
+            QueryTerm::Not(factor) => {
+                write!(f, "!")?;
+                write_factor(f, factor)
+            },
+            QueryTerm::Group(expression) => write!(f, "({})", expression),
+            QueryTerm::FullText(value) => write!(f, "{}", quoted(value)),
+            QueryTerm::Call(name, arguments) => {
+                write!(f, "{}(", text(name))?;
+                write_list(f, arguments, |f, argument| write_argument(f, argument))?;
+                write!(f, ")")
+            },
+            QueryTerm::Reference(name) => write!(f, "@{}", text(name)),
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for QueryExpression<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryExpression::Unary(term) => write!(f, "{}", term),
+            QueryExpression::Binary(term, _, expression) => write!(f, "{} || {}", term, expression),
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for SortKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", if self.descending { "-" } else { "" }, text(&self.key))
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for Aggregate<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.function.name(), self.field.as_ref().map(text).unwrap_or_default())?;
+        if let Some(alias) = &self.alias {
+            write!(f, " as {}", text(alias))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.amount, self.unit.suffix())
+    }
+}
+
+/// How tightly an operator binds, where operators of the same precedence associate to the left
+fn precedence(operator: EvalOperator) -> u8 {
+    match operator {
+        EvalOperator::Multiply | EvalOperator::Divide | EvalOperator::Remainder => 2,
+        EvalOperator::Add | EvalOperator::Subtract => 1,
+        _ => 0,
+    }
+}
+
+/// Write an operand of `operator`, grouping it if it would otherwise bind differently
+fn write_operand<T: AsRef<[u8]>>(f: &mut fmt::Formatter, operand: &EvalExpression<T>, operator: EvalOperator, right: bool) -> fmt::Result {
+    let grouped = match operand {
+        // comparisons cannot be chained, so a comparison in a comparison is always grouped
+        EvalExpression::Binary(_, inner, _) => precedence(*inner) < precedence(operator)
+            || (precedence(*inner) == precedence(operator) && (right || precedence(operator) == 0)),
+        _ => false,
+    };
+    if grouped {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for EvalExpression<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalExpression::Field(field) => write!(f, "{}", text(field)),
+            EvalExpression::Text(value) => write!(f, "{}", quoted(value)),
+            EvalExpression::Number(number) => write!(f, "{}", text(number)),
+            EvalExpression::Call(function, arguments) => {
+                write!(f, "{}(", function.name())?;
+                write_list(f, arguments, |f, argument| write!(f, "{}", argument))?;
+                write!(f, ")")
+            },
+            EvalExpression::Binary(left, operator, right) => {
+                write_operand(f, left, *operator, false)?;
+                write!(f, " {} ", operator.symbol())?;
+                write_operand(f, right, *operator, true)
+            },
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for EvalAssignment<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} = {}", text(&self.field), self.expression)
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for QueryStage<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryStage::Fields(keys) => {
+                write!(f, "fields ")?;
+                write_list(f, keys, |f, key| write!(f, "{}", text(key)))
+            },
+            QueryStage::Sort(keys) => {
+                write!(f, "sort ")?;
+                write_list(f, keys, |f, key| write!(f, "{}", key))
+            },
+            QueryStage::Head(count) => write!(f, "head {}", count),
+            QueryStage::Tail(count) => write!(f, "tail {}", count),
+            QueryStage::Stats { aggregates, by } => {
+                write!(f, "stats ")?;
+                write_list(f, aggregates, |f, aggregate| write!(f, "{}", aggregate))?;
+                if !by.is_empty() {
+                    write!(f, " by ")?;
+                    write_list(f, by, |f, key| write!(f, "{}", text(key)))?;
+                }
+                Ok(())
+            },
+            QueryStage::Timechart { span, aggregates, by } => {
+                write!(f, "timechart span={} ", span)?;
+                write_list(f, aggregates, |f, aggregate| write!(f, "{}", aggregate))?;
+                if let Some(by) = by {
+                    write!(f, " by {}", text(by))?;
+                }
+                Ok(())
+            },
+            QueryStage::Eval(assignments) => {
+                write!(f, "eval ")?;
+                write_list(f, assignments, |f, assignment| write!(f, "{}", assignment))
+            },
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for Query<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tree)?;
+        for stage in self.stages.iter() {
+            write!(f, " | {}", stage)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::query_ast::{parse_query, unescape, Query};
+
+    fn parsed(raw: &str) -> Query<String> {
+        parse_query(raw).unwrap().map(&unescape)
+    }
+
+    fn canonical(raw: &str) -> String {
+        parsed(raw).to_string()
+    }
+
+    #[test]
+    fn test_canonical_form() {
+        let cases = [
+            ("class=A&&(thread=\"main\"||!level>=WARN)", "class = \"A\" && (thread = \"main\" || !level >= \"WARN\")"),
+            ("a=i x || b=*\"x*\" || c!~\"\\\\d\"", "a =i \"x\" || b =* \"x*\" || c !~ \"\\\\d\""),
+            ("a in(x,\"y\") && b not in () && exists( c ) && !missing(d)", "a in (\"x\", \"y\") && b not in () && exists(c) && !missing(d)"),
+            ("\"NullPointerException\" && end_ms>field( start_ms )", "\"NullPointerException\" && end_ms > field(start_ms)"),
+            ("a icontains x|fields a,b|sort -@time,a|head 5|tail 1", "a icontains \"x\" | fields a, b | sort -@time, a | head 5 | tail 1"),
+            ("in_subnet( client,\"10.0.0.0/8\" )&&!business_hours()", "in_subnet(client, \"10.0.0.0/8\") && !business_hours()"),
+            ("client_id=$client&&@time>= $since", "client_id = $client && @time >= $since"),
+            ("@errors&&!( @slow ||@time>x)|head 1", "@errors && !(@slow || @time > \"x\") | head 1"),
+            ("a=x|stats count( ),avg(l) as m by c,d", "a = \"x\" | stats count(), avg(l) as m by c, d"),
+            ("a=x|timechart span = 5m count(user) by verbosity", "a = \"x\" | timechart span=5m count(user) by verbosity"),
+            ("a=x|eval y=(a+b)*2 - c/(d%e),z=if(a-b==\"x\",substr(a,-1),-1)", "a = \"x\" | eval y = (a + b) * 2 - c / (d % e), z = if(a-b == \"x\", substr(a, -1), -1)"),
+            ("a=x|eval y=a - (b - c) + (d + e), z=(a<b)==(c>d)", "a = \"x\" | eval y = a - (b - c) + (d + e), z = (a < b) == (c > d)"),
+            ("a='x' && b in ('y', \"z\") && 'text' && f('x y')", "a = \"x\" && b in (\"y\", \"z\") && \"text\" && f(\"x y\")"),
+            ("a='say \"hi\"' || b=\"it\\'s\" || c=\"\\u{41}\\u{7}\\t\"", "a = \"say \\\"hi\\\"\" || b = \"it's\" || c = \"A\\u{7}\\t\""),
+        ];
+        for (raw, expected) in cases.iter() {
+            assert_eq!(canonical(raw), *expected, "Formatting {}", raw);
+        }
+    }
+
+    #[test]
+    fn test_canonical_form_round_trips() {
+        let cases = [
+            "a=\"x \\\" y\" && (b=1 || (c=2 && d=3)) && !(e=4) && !!f=5",
+            "a='\\\\d+ \\n' && 'x\\u{1F600}' && f(\"a\\\"b\") | eval y = concat('\\'', x)",
+            "a=\"x\" | eval y = a * (b + -1) - (c - d), z = concat(\"a\", upper(b)), w = 1 < 2",
+            "level >= WARN && @time < \"2020/07/17 23:00\" | timechart span=1d min(latency) as fastest, max(latency)",
+        ];
+        for raw in cases.iter() {
+            let formatted = canonical(raw);
+            assert_eq!(parsed(&formatted), parsed(raw), "Parsing {} back", formatted);
+            assert_eq!(canonical(&formatted), formatted, "The canonical form of {} is stable", raw);
+        }
+    }
+}
@@ -1,101 +1,523 @@
 use crate::parser::log_line_parse_result::LogLineParseResult;
+use crate::parser::timestamp::Timestamp;
+use crate::parser::verbosity::Verbosity;
+use crate::query::functions::QueryFunction;
+use regex::Regex;
+use simple_error::{try_with, SimpleError};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
 pub trait Constraint {
     fn check(&self, log_line: &dyn LogLineParseResult) -> bool;
 }
 
-#[derive(Debug, Eq, PartialEq)]
-/// A constraint that just echos a boolean value
-pub struct BooleanConstraint {
-    val: bool,
+/// A constraint on the value of a single field, which can be checked without looking the field up by name
+pub trait FieldConstraint {
+    /// The field this constraint looks at
+    fn get_field_name(&self) -> &str;
+
+    /// Check the value of the field
+    fn check_value(&self, field: &str) -> bool;
+
+    /// Outcome for a log line that did not capture the field, failing unless overridden
+    fn check_absent(&self) -> bool {
+        false
+    }
 }
 
-impl BooleanConstraint {
-    fn new(val: bool) -> Self {
-        Self {
-            val,
+impl<C: FieldConstraint> Constraint for C {
+    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        match log_line.get_field(self.get_field_name()) {
+            Some(field) => self.check_value(field),
+            None => self.check_absent(),
         }
     }
 }
 
-impl Constraint for BooleanConstraint {
-    fn check(&self, _: &dyn LogLineParseResult) -> bool {
-        self.val
+/// A simple equality constraint for a key-value pair
+pub struct SimpleEqualityConstraint {
+    pub field_name: String,
+    pub field_value: String,
+}
+
+impl SimpleEqualityConstraint {
+    /// Create a new equality constraint
+    pub fn new(field_name: &str, field_value: &str) -> Self {
+        SimpleEqualityConstraint {
+            field_name: String::from(field_name),
+            field_value: String::from(field_value),
+        }
     }
 }
 
-// The logical AND of two other constraints
-pub struct ConjunctionConstraint {
-    left: Box<dyn Constraint>,
-    right: Box<dyn Constraint>,
+impl FieldConstraint for SimpleEqualityConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        field == self.field_value
+    }
 }
 
-impl ConjunctionConstraint {
-    pub fn new(left: Box<dyn Constraint>, right: Box<dyn Constraint>) -> Self {
-        Self {
-            left,
-            right,
+/// Compare two field values, numerically when both parse as numbers and lexicographically otherwise
+pub fn compare_values(left: &str, right: &str) -> Ordering {
+    if let (Ok(left_number), Ok(right_number)) = (left.parse::<f64>(), right.parse::<f64>()) {
+        if let Some(ordering) = left_number.partial_cmp(&right_number) {
+            return ordering
         }
     }
+    left.cmp(right)
 }
 
-impl Constraint for ConjunctionConstraint {
-    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
-        self.left.check(log_line) && self.right.check(log_line)
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+/// Relationship that has to hold between a field and a value for a comparison to pass
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl Comparison {
+    /// Whether the ordering of a field relative to a value satisfies this comparison
+    pub fn accepts(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::LessThan => ordering == Ordering::Less,
+            Comparison::LessThanOrEqual => ordering != Ordering::Greater,
+            Comparison::GreaterThan => ordering == Ordering::Greater,
+            Comparison::GreaterThanOrEqual => ordering != Ordering::Less,
+        }
     }
 }
 
-// The logical OR of two constraints
-pub struct DisjunctionConstraint {
-    left: Box<dyn Constraint>,
-    right: Box<dyn Constraint>,
+/// An inequality or ordering constraint for a key-value pair
+pub struct ComparisonConstraint {
+    pub field_name: String,
+    pub field_value: String,
+    pub comparison: Comparison,
 }
 
-impl DisjunctionConstraint {
-    pub fn new(left: Box<dyn Constraint>, right: Box<dyn Constraint>) -> Self {
-        Self {
-            left,
-            right,
+impl ComparisonConstraint {
+    /// Create a new comparison constraint
+    pub fn new(field_name: &str, comparison: Comparison, field_value: &str) -> Self {
+        ComparisonConstraint {
+            field_name: String::from(field_name),
+            field_value: String::from(field_value),
+            comparison,
         }
     }
 }
 
-impl Constraint for DisjunctionConstraint {
-    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
-        self.left.check(log_line) || self.right.check(log_line)
+impl FieldConstraint for ComparisonConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        self.comparison.accepts(compare_values(field, &self.field_value))
     }
 }
 
-/// A simple equality constraint for a key-value pair
-pub struct SimpleEqualityConstraint {
+/// A constraint that a field does, or does not, contain a match for a regular expression
+pub struct RegexConstraint {
+    pub field_name: String,
+    pub pattern: Regex,
+    pub negated: bool,
+}
+
+impl RegexConstraint {
+    /// Create a new regex constraint, compiling the pattern up front
+    pub fn new(field_name: &str, pattern: &str, negated: bool) -> Result<Self, SimpleError> {
+        Ok(RegexConstraint {
+            field_name: String::from(field_name),
+            pattern: try_with!(Regex::new(pattern), "Unable to compile pattern {}", pattern),
+            negated,
+        })
+    }
+}
+
+impl FieldConstraint for RegexConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        self.pattern.is_match(field) != self.negated
+    }
+}
+
+/// A constraint that a field equals a value, ignoring case
+pub struct CaseInsensitiveEqualityConstraint {
     pub field_name: String,
     pub field_value: String,
 }
 
-impl SimpleEqualityConstraint {
-    /// Create a new equality constraint
+impl CaseInsensitiveEqualityConstraint {
+    /// Create a new case insensitive equality constraint, folding the expected value up front
     pub fn new(field_name: &str, field_value: &str) -> Self {
-        SimpleEqualityConstraint {
+        CaseInsensitiveEqualityConstraint {
             field_name: String::from(field_name),
-            field_value: String::from(field_value),
+            field_value: field_value.to_lowercase(),
+        }
+    }
+}
+
+impl FieldConstraint for CaseInsensitiveEqualityConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        // compare lazily to avoid allocating a lowercased copy of every field
+        field.chars().flat_map(char::to_lowercase).eq(self.field_value.chars())
+    }
+}
+
+/// A constraint that a whole field matches a glob, where `*` matches any run of characters
+/// and `?` matches any single character
+pub struct GlobConstraint {
+    pub field_name: String,
+    pub pattern: Regex,
+}
+
+impl GlobConstraint {
+    /// Create a new glob constraint, compiling the glob to an anchored regular expression
+    pub fn new(field_name: &str, glob: &str) -> Result<Self, SimpleError> {
+        let mut pattern = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                _ => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        pattern.push('$');
+        Ok(GlobConstraint {
+            field_name: String::from(field_name),
+            pattern: try_with!(Regex::new(&pattern), "Unable to compile glob {}", glob),
+        })
+    }
+}
+
+impl FieldConstraint for GlobConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        self.pattern.is_match(field)
+    }
+}
+
+/// Lowercase a value when a constraint ignores case
+fn fold_case(value: &str, case_insensitive: bool) -> Cow<'_, str> {
+    if case_insensitive {
+        Cow::Owned(value.to_lowercase())
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// A constraint that some captured field of a log line contains a piece of text
+pub struct FullTextConstraint {
+    pub text: String,
+}
+
+impl FullTextConstraint {
+    /// Create a new full text constraint
+    pub fn new(text: &str) -> Self {
+        FullTextConstraint {
+            text: String::from(text),
+        }
+    }
+}
+
+impl Constraint for FullTextConstraint {
+    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        log_line.get_values().any(|field| field.contains(self.text.as_str()))
+    }
+}
+
+/// A constraint that a registered function accepts a log line
+pub struct FunctionConstraint {
+    pub function: QueryFunction,
+    pub arguments: Vec<String>,
+}
+
+impl FunctionConstraint {
+    /// Create a new function constraint
+    pub fn new(function: QueryFunction, arguments: Vec<String>) -> Self {
+        FunctionConstraint {
+            function,
+            arguments,
+        }
+    }
+}
+
+impl Constraint for FunctionConstraint {
+    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        (self.function)(log_line, &self.arguments)
+    }
+}
+
+/// A constraint on the time a log line was written at
+pub struct TimestampConstraint {
+    pub timestamp: Timestamp,
+    pub comparison: Comparison,
+}
+
+impl TimestampConstraint {
+    /// Create a new timestamp constraint
+    pub fn new(comparison: Comparison, timestamp: Timestamp) -> Self {
+        TimestampConstraint {
+            timestamp,
+            comparison,
+        }
+    }
+}
+
+impl Constraint for TimestampConstraint {
+    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        if let Some(timestamp) = log_line.get_timestamp() {
+            return self.comparison.accepts(timestamp.cmp(&self.timestamp))
+        }
+        false
+    }
+}
+
+/// A constraint on the severity of a log line
+pub struct VerbosityConstraint {
+    pub verbosity: Verbosity,
+    pub comparison: Comparison,
+}
+
+impl VerbosityConstraint {
+    /// Create a new verbosity constraint
+    pub fn new(comparison: Comparison, verbosity: Verbosity) -> Self {
+        VerbosityConstraint {
+            verbosity,
+            comparison,
         }
     }
 }
 
-impl Constraint for SimpleEqualityConstraint {
+impl Constraint for VerbosityConstraint {
     fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
-        if let Some(field) = log_line.get_field(&self.field_name) {
-            return *field == self.field_value
+        if let Some(verbosity) = log_line.get_verbosity() {
+            return self.comparison.accepts(verbosity.cmp(&self.verbosity))
         }
         false
     }
 }
 
+/// A constraint that a field contains a substring
+pub struct ContainsConstraint {
+    pub field_name: String,
+    pub field_value: String,
+    pub case_insensitive: bool,
+}
+
+impl ContainsConstraint {
+    /// Create a new containment constraint
+    pub fn new(field_name: &str, field_value: &str, case_insensitive: bool) -> Self {
+        ContainsConstraint {
+            field_name: String::from(field_name),
+            field_value: fold_case(field_value, case_insensitive).into_owned(),
+            case_insensitive,
+        }
+    }
+}
+
+impl FieldConstraint for ContainsConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        fold_case(field, self.case_insensitive).contains(self.field_value.as_str())
+    }
+}
+
+/// A constraint that a field begins with a prefix
+pub struct StartsWithConstraint {
+    pub field_name: String,
+    pub field_value: String,
+    pub case_insensitive: bool,
+}
+
+impl StartsWithConstraint {
+    /// Create a new prefix constraint
+    pub fn new(field_name: &str, field_value: &str, case_insensitive: bool) -> Self {
+        StartsWithConstraint {
+            field_name: String::from(field_name),
+            field_value: fold_case(field_value, case_insensitive).into_owned(),
+            case_insensitive,
+        }
+    }
+}
+
+impl FieldConstraint for StartsWithConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        fold_case(field, self.case_insensitive).starts_with(self.field_value.as_str())
+    }
+}
+
+/// A constraint that a field ends with a suffix
+pub struct EndsWithConstraint {
+    pub field_name: String,
+    pub field_value: String,
+    pub case_insensitive: bool,
+}
+
+impl EndsWithConstraint {
+    /// Create a new suffix constraint
+    pub fn new(field_name: &str, field_value: &str, case_insensitive: bool) -> Self {
+        EndsWithConstraint {
+            field_name: String::from(field_name),
+            field_value: fold_case(field_value, case_insensitive).into_owned(),
+            case_insensitive,
+        }
+    }
+}
+
+impl FieldConstraint for EndsWithConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        fold_case(field, self.case_insensitive).ends_with(self.field_value.as_str())
+    }
+}
+
+/// A constraint that a field is, or is not, one of a set of values
+pub struct SetMembershipConstraint {
+    pub field_name: String,
+    pub field_values: HashSet<String>,
+    pub negated: bool,
+}
+
+impl SetMembershipConstraint {
+    /// Create a new set membership constraint
+    pub fn new(field_name: &str, field_values: &[&str], negated: bool) -> Self {
+        SetMembershipConstraint {
+            field_name: String::from(field_name),
+            field_values: field_values.iter().map(|value| String::from(*value)).collect(),
+            negated,
+        }
+    }
+}
+
+impl FieldConstraint for SetMembershipConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, field: &str) -> bool {
+        self.field_values.contains(field) != self.negated
+    }
+}
+
+/// A constraint that a field was, or was not, captured for a log line
+pub struct ExistenceConstraint {
+    pub field_name: String,
+    pub negated: bool,
+}
+
+impl ExistenceConstraint {
+    /// Create a new existence constraint
+    pub fn new(field_name: &str, negated: bool) -> Self {
+        ExistenceConstraint {
+            field_name: String::from(field_name),
+            negated,
+        }
+    }
+}
+
+impl FieldConstraint for ExistenceConstraint {
+    fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    fn check_value(&self, _: &str) -> bool {
+        !self.negated
+    }
+
+    fn check_absent(&self) -> bool {
+        self.negated
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+/// Relationship that has to hold between the values of two fields
+pub enum FieldRelation {
+    Equal,
+    EqualIgnoringCase,
+    Compare(Comparison),
+    Contains { case_insensitive: bool },
+    StartsWith { case_insensitive: bool },
+    EndsWith { case_insensitive: bool },
+}
+
+/// A constraint relating the values of two fields of a log line, like `end_ms > field(start_ms)`
+pub struct FieldComparisonConstraint {
+    pub field_name: String,
+    pub other_field_name: String,
+    pub relation: FieldRelation,
+}
+
+impl FieldComparisonConstraint {
+    /// Create a new constraint between two fields
+    pub fn new(field_name: &str, relation: FieldRelation, other_field_name: &str) -> Self {
+        FieldComparisonConstraint {
+            field_name: String::from(field_name),
+            other_field_name: String::from(other_field_name),
+            relation,
+        }
+    }
+
+    /// Check the values of the two fields, which behaves like checking the first one against
+    /// a literal holding the value of the second one
+    pub fn check_values(&self, field: &str, other: &str) -> bool {
+        match self.relation {
+            FieldRelation::Equal => field == other,
+            FieldRelation::EqualIgnoringCase => field.chars().flat_map(char::to_lowercase).eq(other.chars().flat_map(char::to_lowercase)),
+            FieldRelation::Compare(comparison) => comparison.accepts(compare_values(field, other)),
+            FieldRelation::Contains { case_insensitive } =>
+                fold_case(field, case_insensitive).contains(fold_case(other, case_insensitive).as_ref()),
+            FieldRelation::StartsWith { case_insensitive } =>
+                fold_case(field, case_insensitive).starts_with(fold_case(other, case_insensitive).as_ref()),
+            FieldRelation::EndsWith { case_insensitive } =>
+                fold_case(field, case_insensitive).ends_with(fold_case(other, case_insensitive).as_ref()),
+        }
+    }
+}
+
+impl Constraint for FieldComparisonConstraint {
+    fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        match (log_line.get_field(&self.field_name), log_line.get_field(&self.other_field_name)) {
+            (Some(field), Some(other)) => self.check_values(field, other),
+            _ => false,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::parser::{default_log_line_parse_result::DefaultLogLineParseResult};
     use std::collections::HashMap;
+    use std::rc::Rc;
 
     fn noop_log_line() -> DefaultLogLineParseResult {
         DefaultLogLineParseResult::new(
@@ -113,48 +535,172 @@ mod tests {
     }
 
     #[test]
-    fn test_simple_boolean_constraint() {
-        let constraint = BooleanConstraint::new(true);
-        let log_line = noop_log_line();
-        assert_eq!(constraint.check(&log_line), true);
+    fn test_comparison_constraint_numeric() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("latency"), String::from("90"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(ComparisonConstraint::new("latency", Comparison::LessThan, "500").check(&log_line), true, "90 is numerically less than 500");
+        assert_eq!(ComparisonConstraint::new("latency", Comparison::GreaterThanOrEqual, "500").check(&log_line), false);
+        assert_eq!(ComparisonConstraint::new("latency", Comparison::LessThanOrEqual, "90.0").check(&log_line), true);
+        assert_eq!(ComparisonConstraint::new("latency", Comparison::NotEqual, "90.0").check(&log_line), false);
+    }
+
+    #[test]
+    fn test_comparison_constraint_lexicographic() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("class"), String::from("Beta"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(ComparisonConstraint::new("class", Comparison::GreaterThan, "Alpha").check(&log_line), true);
+        assert_eq!(ComparisonConstraint::new("class", Comparison::LessThan, "Alpha").check(&log_line), false);
+        assert_eq!(ComparisonConstraint::new("class", Comparison::NotEqual, "Alpha").check(&log_line), true);
+        assert_eq!(ComparisonConstraint::new("missing", Comparison::NotEqual, "Alpha").check(&log_line), false, "Missing fields never pass");
+    }
+
+    #[test]
+    fn test_regex_constraint() -> Result<(), SimpleError> {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("content"), String::from("Process snapshot: Snapshotting not enabled"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(RegexConstraint::new("content", "Snapshotting.*enabled", false)?.check(&log_line), true, "Pattern matches a fragment of the field");
+        assert_eq!(RegexConstraint::new("content", "Snapshotting.*enabled", true)?.check(&log_line), false);
+        assert_eq!(RegexConstraint::new("content", "^snapshot", true)?.check(&log_line), true);
+        assert_eq!(RegexConstraint::new("missing", "^snapshot", true)?.check(&log_line), false, "Missing fields never pass");
+        assert!(RegexConstraint::new("content", "(unclosed", false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_equality_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("class"), String::from("ImageManagerImpl"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(CaseInsensitiveEqualityConstraint::new("class", "imagemanagerimpl").check(&log_line), true);
+        assert_eq!(CaseInsensitiveEqualityConstraint::new("class", "IMAGEMANAGERIMPL").check(&log_line), true);
+        assert_eq!(CaseInsensitiveEqualityConstraint::new("class", "imagemanager").check(&log_line), false, "The whole field has to match");
+        assert_eq!(CaseInsensitiveEqualityConstraint::new("thread", "imagemanagerimpl").check(&log_line), false);
+    }
+
+    #[test]
+    fn test_glob_constraint() -> Result<(), SimpleError> {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("thread"), String::from("dispatcher-12"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(GlobConstraint::new("thread", "dispatcher-*")?.check(&log_line), true);
+        assert_eq!(GlobConstraint::new("thread", "*-1?")?.check(&log_line), true);
+        assert_eq!(GlobConstraint::new("thread", "dispatcher-?")?.check(&log_line), false);
+        assert_eq!(GlobConstraint::new("thread", "dispatcher")?.check(&log_line), false, "Globs are anchored at both ends");
+        assert_eq!(GlobConstraint::new("thread", "dispatcher.12")?.check(&log_line), false, "Regex syntax is matched literally");
+        assert_eq!(GlobConstraint::new("class", "*")?.check(&log_line), false);
+        Ok(())
+    }
 
-        let constraint = BooleanConstraint::new(false);
-        let log_line = noop_log_line();
-        assert_eq!(constraint.check(&log_line), false);
+    #[test]
+    fn test_full_text_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [
+                (String::from("class"), String::from("ImageManagerImpl")),
+                (String::from("content"), String::from("java.lang.NullPointerException: image")),
+            ].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(FullTextConstraint::new("NullPointerException").check(&log_line), true);
+        assert_eq!(FullTextConstraint::new("Manager").check(&log_line), true);
+        assert_eq!(FullTextConstraint::new("OutOfMemoryError").check(&log_line), false);
+        assert_eq!(FullTextConstraint::new("").check(&noop_log_line()), false, "Lines without captures never pass");
     }
 
     #[test]
-    fn test_conjunction_constraint() {
-        let bool_constraint_left = Box::new(BooleanConstraint::new(false));
-        let bool_constraint_right = Box::new(BooleanConstraint::new(false));
-        let conj_constraint = ConjunctionConstraint::new(bool_constraint_left, bool_constraint_right);
-        let log_line = noop_log_line();
-        assert_eq!(conj_constraint.check(&log_line), false);
+    fn test_function_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("client"), String::from("10.1.2.3"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        let starts_with: QueryFunction = Rc::new(|log_line, arguments| {
+            log_line.get_field(&arguments[0]).is_some_and(|value| value.starts_with(arguments[1].as_str()))
+        });
+        let constraint = |prefix: &str| FunctionConstraint::new(starts_with.clone(), vec![String::from("client"), String::from(prefix)]);
+        assert_eq!(constraint("10.").check(&log_line), true);
+        assert_eq!(constraint("192.168.").check(&log_line), false);
+        assert_eq!(constraint("10.").check(&noop_log_line()), false);
+    }
 
-        let bool_constraint_left = Box::new(BooleanConstraint::new(false));
-        let bool_constraint_right = Box::new(BooleanConstraint::new(true));
-        let conj_constraint = ConjunctionConstraint::new(bool_constraint_left, bool_constraint_right);
-        assert_eq!(conj_constraint.check(&log_line), false);
+    #[test]
+    fn test_timestamp_constraint() -> Result<(), SimpleError> {
+        let log_line = DefaultLogLineParseResult::with_timestamp(
+            HashMap::new(),
+            Some("2020/07/17 23:12:30.037".parse()?),
+        );
+        assert_eq!(TimestampConstraint::new(Comparison::GreaterThanOrEqual, "2020/07/17 23:00:00".parse()?).check(&log_line), true);
+        assert_eq!(TimestampConstraint::new(Comparison::LessThan, "2020/07/17 23:00:00".parse()?).check(&log_line), false);
+        assert_eq!(TimestampConstraint::new(Comparison::Equal, "2020/07/17 23:12:30.037".parse()?).check(&log_line), true);
+        assert_eq!(TimestampConstraint::new(Comparison::LessThan, "2020/07/18".parse()?).check(&noop_log_line()), false, "Lines without a timestamp never pass");
+        Ok(())
+    }
 
-        let bool_constraint_left = Box::new(BooleanConstraint::new(true));
-        let bool_constraint_right = Box::new(BooleanConstraint::new(true));
-        let conj_constraint = ConjunctionConstraint::new(bool_constraint_left, bool_constraint_right);
-        assert_eq!(conj_constraint.check(&log_line), true);
+    #[test]
+    fn test_verbosity_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("verbosity"), String::from("ERROR"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(VerbosityConstraint::new(Comparison::GreaterThanOrEqual, Verbosity::Warn).check(&log_line), true);
+        assert_eq!(VerbosityConstraint::new(Comparison::GreaterThan, Verbosity::Error).check(&log_line), false);
+        assert_eq!(VerbosityConstraint::new(Comparison::LessThan, Verbosity::Fatal).check(&log_line), true);
+        assert_eq!(VerbosityConstraint::new(Comparison::GreaterThanOrEqual, Verbosity::Trace).check(&noop_log_line()), false, "Lines without a verbosity never pass");
     }
 
     #[test]
-    fn test_disjunction_constraint() {
-        let bool_constraint_left = Box::new(BooleanConstraint::new(false));
-        let bool_constraint_right = Box::new(BooleanConstraint::new(false));
-        let conj_constraint = DisjunctionConstraint::new(bool_constraint_left, bool_constraint_right);
-        let log_line = noop_log_line();
-        assert_eq!(conj_constraint.check(&log_line), false);
+    fn test_containment_constraints() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("thread"), String::from("ImageManagerImpl-dispatcher"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(ContainsConstraint::new("thread", "Impl-", false).check(&log_line), true);
+        assert_eq!(ContainsConstraint::new("thread", "impl-", false).check(&log_line), false);
+        assert_eq!(ContainsConstraint::new("thread", "impl-", true).check(&log_line), true);
+        assert_eq!(StartsWithConstraint::new("thread", "ImageManager", false).check(&log_line), true);
+        assert_eq!(StartsWithConstraint::new("thread", "IMAGEMANAGER", true).check(&log_line), true);
+        assert_eq!(StartsWithConstraint::new("thread", "dispatcher", false).check(&log_line), false);
+        assert_eq!(EndsWithConstraint::new("thread", "dispatcher", false).check(&log_line), true);
+        assert_eq!(EndsWithConstraint::new("thread", "Dispatcher", false).check(&log_line), false);
+        assert_eq!(EndsWithConstraint::new("thread", "Dispatcher", true).check(&log_line), true);
+        assert_eq!(ContainsConstraint::new("missing", "", false).check(&log_line), false, "Missing fields never pass");
+    }
 
-        let bool_constraint_left = Box::new(BooleanConstraint::new(false));
-        let bool_constraint_right = Box::new(BooleanConstraint::new(true));
-        let conj_constraint = DisjunctionConstraint::new(bool_constraint_left, bool_constraint_right);
-        let log_line = noop_log_line();
-        assert_eq!(conj_constraint.check(&log_line), true);
+    #[test]
+    fn test_set_membership_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("class"), String::from("B"))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(SetMembershipConstraint::new("class", &["A", "B", "C"], false).check(&log_line), true);
+        assert_eq!(SetMembershipConstraint::new("class", &["A", "C"], false).check(&log_line), false);
+        assert_eq!(SetMembershipConstraint::new("class", &["A", "B", "C"], true).check(&log_line), false);
+        assert_eq!(SetMembershipConstraint::new("class", &[], true).check(&log_line), true);
+        assert_eq!(SetMembershipConstraint::new("missing", &["A"], true).check(&log_line), false, "Missing fields never pass");
+    }
 
+    #[test]
+    fn test_existence_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [(String::from("client_id"), String::from(""))].iter().cloned().collect::<HashMap<String, String>>(),
+        );
+        assert_eq!(ExistenceConstraint::new("client_id", false).check(&log_line), true, "Empty captures exist");
+        assert_eq!(ExistenceConstraint::new("client_id", true).check(&log_line), false);
+        assert_eq!(ExistenceConstraint::new("user", false).check(&log_line), false);
+        assert_eq!(ExistenceConstraint::new("user", true).check(&log_line), true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_field_comparison_constraint() {
+        let log_line = DefaultLogLineParseResult::new(
+            [("start_ms", "90"), ("end_ms", "500"), ("thread", "Main-dispatcher"), ("application", "main")]
+                .iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value)))
+                .collect::<HashMap<String, String>>(),
+        );
+        let check = |field: &str, relation: FieldRelation, other: &str| FieldComparisonConstraint::new(field, relation, other).check(&log_line);
+        assert_eq!(check("end_ms", FieldRelation::Compare(Comparison::GreaterThan), "start_ms"), true, "500 is numerically greater than 90");
+        assert_eq!(check("end_ms", FieldRelation::Compare(Comparison::LessThanOrEqual), "start_ms"), false);
+        assert_eq!(check("thread", FieldRelation::Equal, "application"), false);
+        assert_eq!(check("thread", FieldRelation::StartsWith { case_insensitive: false }, "application"), false);
+        assert_eq!(check("thread", FieldRelation::StartsWith { case_insensitive: true }, "application"), true);
+        assert_eq!(check("application", FieldRelation::EqualIgnoringCase, "application"), true);
+        assert_eq!(check("thread", FieldRelation::Compare(Comparison::NotEqual), "user"), false, "Missing fields fail every relation");
+    }
+}
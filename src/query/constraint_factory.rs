@@ -1,34 +1,283 @@
+use crate::parser::default_log_line_parse_result::VERBOSITY_FIELDS;
+use crate::parser::timestamp::Timestamp;
+use crate::parser::verbosity::Verbosity;
 use crate::query::constraint::*;
-use crate::query::query_ast::{QueryAtom, QueryConstraint, QueryExpression, QueryTerm, QueryOpTerm, QueryOpExpression, Query};
+use crate::query::functions::QueryFunctions;
+use crate::query::optimizer::QueryPlan;
+use crate::query::program::{FieldRef, Instruction, Program};
+use crate::query::query_ast::{QueryAtom, QueryConstraint, QueryExpression, QueryTerm, QueryValue};
+use simple_error::{bail, try_with, SimpleError};
 use std::str::from_utf8;
 
-pub fn atom_converter(atom: QueryAtom<&[u8]>) -> Box<dyn Constraint> {
-    match atom.query_constraint {
-        QueryConstraint::EQ => Box::new(SimpleEqualityConstraint::new(from_utf8(atom.query_key).unwrap(), from_utf8(atom.query_value).unwrap()))
+/// Key that refers to the timestamp of a log line rather than one of its captures
+pub const TIMESTAMP_KEY: &str = "@time";
+
+/// Key that compares the verbosity of a log line by severity rather than as a string
+pub const LEVEL_KEY: &str = "@level";
+
+/// Text of a key or value, which is valid UTF-8 as it was either parsed from a string or is one
+fn text<T: AsRef<[u8]>>(part: &T) -> &str {
+    from_utf8(part.as_ref()).unwrap()
+}
+
+pub fn atom_converter<T: AsRef<[u8]>>(atom: QueryAtom<T>) -> Result<Instruction, SimpleError> {
+    let key = text(&atom.query_key);
+    let constraint = match &atom.query_value {
+        QueryValue::Literal(value) if key == TIMESTAMP_KEY => return Ok(Instruction::Line(timestamp_atom_converter(atom.query_constraint, text(value))?)),
+        QueryValue::Literal(value) if key == LEVEL_KEY => return Ok(Instruction::Line(level_atom_converter(atom.query_constraint, text(value))?)),
+        QueryValue::Literal(value) => literal_atom_converter(key, atom.query_constraint, text(value))?,
+        QueryValue::Field(other) => return field_atom_converter(key, atom.query_constraint, text(other)),
+        QueryValue::List(values) => list_atom_converter(key, atom.query_constraint, values.iter().map(text).collect())?,
+        QueryValue::Parameter(name) => bail!("No value given for parameter ${}", text(name)),
+        QueryValue::Empty => key_atom_converter(key, atom.query_constraint)?,
+    };
+    Ok(Instruction::Field(FieldRef::Name(String::from(key)), constraint))
+}
+
+/// Reject ordering a capture the verbosity is read from by a level name, which would compare
+/// the names as text, e.g. `level >= WARN` instead of `@level >= WARN`
+fn severity_checker(key: &str, query_constraint: &QueryConstraint, value: &str) -> Result<(), SimpleError> {
+    let ordering = matches!(query_constraint, QueryConstraint::LT | QueryConstraint::LE | QueryConstraint::GT | QueryConstraint::GE);
+    if ordering && VERBOSITY_FIELDS.contains(&key) && value.parse::<Verbosity>().is_ok() {
+        bail!("Constraint {:?} on {} would compare {} as text, use {} to compare by severity", query_constraint, key, value, LEVEL_KEY)
+    }
+    Ok(())
+}
+
+/// Build a constraint relating a key to a single value
+fn literal_atom_converter(key: &str, query_constraint: QueryConstraint, value: &str) -> Result<Box<dyn FieldConstraint>, SimpleError> {
+    severity_checker(key, &query_constraint, value)?;
+    Ok(match query_constraint {
+        QueryConstraint::EQ => Box::new(SimpleEqualityConstraint::new(key, value)),
+        QueryConstraint::IEQ => Box::new(CaseInsensitiveEqualityConstraint::new(key, value)),
+        QueryConstraint::GLOB => Box::new(GlobConstraint::new(key, value)?),
+        QueryConstraint::NE => Box::new(ComparisonConstraint::new(key, Comparison::NotEqual, value)),
+        QueryConstraint::LT => Box::new(ComparisonConstraint::new(key, Comparison::LessThan, value)),
+        QueryConstraint::LE => Box::new(ComparisonConstraint::new(key, Comparison::LessThanOrEqual, value)),
+        QueryConstraint::GT => Box::new(ComparisonConstraint::new(key, Comparison::GreaterThan, value)),
+        QueryConstraint::GE => Box::new(ComparisonConstraint::new(key, Comparison::GreaterThanOrEqual, value)),
+        QueryConstraint::MATCH => Box::new(RegexConstraint::new(key, value, false)?),
+        QueryConstraint::NOTMATCH => Box::new(RegexConstraint::new(key, value, true)?),
+        QueryConstraint::CONTAINS => Box::new(ContainsConstraint::new(key, value, false)),
+        QueryConstraint::STARTSWITH => Box::new(StartsWithConstraint::new(key, value, false)),
+        QueryConstraint::ENDSWITH => Box::new(EndsWithConstraint::new(key, value, false)),
+        QueryConstraint::ICONTAINS => Box::new(ContainsConstraint::new(key, value, true)),
+        QueryConstraint::ISTARTSWITH => Box::new(StartsWithConstraint::new(key, value, true)),
+        QueryConstraint::IENDSWITH => Box::new(EndsWithConstraint::new(key, value, true)),
+        QueryConstraint::IN | QueryConstraint::NOTIN => bail!("Constraint {:?} on {} expects a list of values", query_constraint, key),
+        QueryConstraint::EXISTS | QueryConstraint::MISSING => bail!("Constraint {:?} on {} does not take a value", query_constraint, key),
+    })
+}
+
+/// Build an instruction relating a key to the value of another field
+fn field_atom_converter(key: &str, query_constraint: QueryConstraint, other: &str) -> Result<Instruction, SimpleError> {
+    let relation = field_relation(key, query_constraint, other)?;
+    Ok(Instruction::Compare(
+        FieldRef::Name(String::from(key)),
+        FieldRef::Name(String::from(other)),
+        FieldComparisonConstraint::new(key, relation, other),
+    ))
+}
+
+/// The relation a constraint checks between a key and another field
+fn field_relation(key: &str, query_constraint: QueryConstraint, other: &str) -> Result<FieldRelation, SimpleError> {
+    if [key, other].iter().any(|key| *key == TIMESTAMP_KEY || *key == LEVEL_KEY) {
+        bail!("Cannot compare {} to the field {}", key, other)
+    }
+    Ok(match query_constraint {
+        QueryConstraint::EQ => FieldRelation::Equal,
+        QueryConstraint::IEQ => FieldRelation::EqualIgnoringCase,
+        QueryConstraint::NE => FieldRelation::Compare(Comparison::NotEqual),
+        QueryConstraint::LT => FieldRelation::Compare(Comparison::LessThan),
+        QueryConstraint::LE => FieldRelation::Compare(Comparison::LessThanOrEqual),
+        QueryConstraint::GT => FieldRelation::Compare(Comparison::GreaterThan),
+        QueryConstraint::GE => FieldRelation::Compare(Comparison::GreaterThanOrEqual),
+        QueryConstraint::CONTAINS => FieldRelation::Contains { case_insensitive: false },
+        QueryConstraint::STARTSWITH => FieldRelation::StartsWith { case_insensitive: false },
+        QueryConstraint::ENDSWITH => FieldRelation::EndsWith { case_insensitive: false },
+        QueryConstraint::ICONTAINS => FieldRelation::Contains { case_insensitive: true },
+        QueryConstraint::ISTARTSWITH => FieldRelation::StartsWith { case_insensitive: true },
+        QueryConstraint::IENDSWITH => FieldRelation::EndsWith { case_insensitive: true },
+        _ => bail!("Constraint {:?} on {} cannot compare to a field", query_constraint, key),
+    })
+}
+
+/// The comparison performed by an ordering constraint, for keys that are compared with a dedicated ordering
+fn ordering_comparison(key: &str, query_constraint: QueryConstraint) -> Result<Comparison, SimpleError> {
+    Ok(match query_constraint {
+        QueryConstraint::EQ => Comparison::Equal,
+        QueryConstraint::NE => Comparison::NotEqual,
+        QueryConstraint::LT => Comparison::LessThan,
+        QueryConstraint::LE => Comparison::LessThanOrEqual,
+        QueryConstraint::GT => Comparison::GreaterThan,
+        QueryConstraint::GE => Comparison::GreaterThanOrEqual,
+        _ => bail!("Constraint {:?} is not supported on {}", query_constraint, key),
+    })
+}
+
+/// Build a constraint comparing the timestamp of a log line to a point in time
+fn timestamp_atom_converter(query_constraint: QueryConstraint, value: &str) -> Result<Box<dyn Constraint>, SimpleError> {
+    let (comparison, timestamp) = timestamp_operands(query_constraint, value)?;
+    Ok(Box::new(TimestampConstraint::new(comparison, timestamp)))
+}
+
+/// The comparison and point in time of a constraint on the timestamp of a log line
+fn timestamp_operands(query_constraint: QueryConstraint, value: &str) -> Result<(Comparison, Timestamp), SimpleError> {
+    let comparison = ordering_comparison(TIMESTAMP_KEY, query_constraint)?;
+    Ok((comparison, try_with!(value.parse(), "Invalid value for {}", TIMESTAMP_KEY)))
+}
+
+/// Build a constraint comparing the severity of a log line to a verbosity level
+fn level_atom_converter(query_constraint: QueryConstraint, value: &str) -> Result<Box<dyn Constraint>, SimpleError> {
+    let (comparison, verbosity) = level_operands(query_constraint, value)?;
+    Ok(Box::new(VerbosityConstraint::new(comparison, verbosity)))
+}
+
+/// The comparison and verbosity level of a constraint on the severity of a log line
+fn level_operands(query_constraint: QueryConstraint, value: &str) -> Result<(Comparison, Verbosity), SimpleError> {
+    let comparison = ordering_comparison(LEVEL_KEY, query_constraint)?;
+    Ok((comparison, try_with!(value.parse(), "Invalid value for {}", LEVEL_KEY)))
+}
+
+/// Build a constraint relating a key to a list of values
+fn list_atom_converter(key: &str, query_constraint: QueryConstraint, values: Vec<&str>) -> Result<Box<dyn FieldConstraint>, SimpleError> {
+    Ok(match query_constraint {
+        QueryConstraint::IN => Box::new(SetMembershipConstraint::new(key, &values, false)),
+        QueryConstraint::NOTIN => Box::new(SetMembershipConstraint::new(key, &values, true)),
+        _ => bail!("Constraint {:?} on {} expects a single value", query_constraint, key),
+    })
+}
+
+/// Build a constraint that only looks at a key
+fn key_atom_converter(key: &str, query_constraint: QueryConstraint) -> Result<Box<dyn FieldConstraint>, SimpleError> {
+    Ok(match query_constraint {
+        QueryConstraint::EXISTS => Box::new(ExistenceConstraint::new(key, false)),
+        QueryConstraint::MISSING => Box::new(ExistenceConstraint::new(key, true)),
+        _ => bail!("Constraint {:?} on {} expects a value", query_constraint, key),
+    })
+}
+
+/// Check that a constraint can be built from an atom without building it, so that patterns
+/// are only compiled by `atom_converter` for the atoms left after optimizing
+fn atom_checker<T: AsRef<[u8]>>(atom: &QueryAtom<T>) -> Result<(), SimpleError> {
+    let key = text(&atom.query_key);
+    let query_constraint = atom.query_constraint.clone();
+    // the converters called below reject their atom before building anything
+    match &atom.query_value {
+        QueryValue::Literal(value) if key == TIMESTAMP_KEY => timestamp_operands(query_constraint, text(value)).map(|_| ()),
+        QueryValue::Literal(value) if key == LEVEL_KEY => level_operands(query_constraint, text(value)).map(|_| ()),
+        QueryValue::Literal(value) => match query_constraint {
+            QueryConstraint::LT | QueryConstraint::LE | QueryConstraint::GT | QueryConstraint::GE => severity_checker(key, &query_constraint, text(value)),
+            QueryConstraint::MATCH | QueryConstraint::NOTMATCH => pattern_checker(text(value)),
+            QueryConstraint::IN | QueryConstraint::NOTIN | QueryConstraint::EXISTS | QueryConstraint::MISSING => {
+                literal_atom_converter(key, query_constraint, text(value)).map(|_| ())
+            },
+            // globs are escaped into patterns that always parse
+            _ => Ok(()),
+        },
+        QueryValue::Field(other) => field_relation(key, query_constraint, text(other)).map(|_| ()),
+        QueryValue::List(values) if !query_constraint.takes_list() => list_atom_converter(key, query_constraint, values.iter().map(text).collect()).map(|_| ()),
+        QueryValue::List(_) => Ok(()),
+        QueryValue::Parameter(name) => bail!("No value given for parameter ${}", text(name)),
+        QueryValue::Empty => match query_constraint {
+            QueryConstraint::EXISTS | QueryConstraint::MISSING => Ok(()),
+            _ => key_atom_converter(key, query_constraint).map(|_| ()),
+        },
     }
 }
 
-pub fn term_converter(term: QueryTerm<&[u8]>) -> Box<dyn Constraint> {
+/// Check the syntax of a regular expression without compiling it
+fn pattern_checker(pattern: &str) -> Result<(), SimpleError> {
+    try_with!(regex_syntax::Parser::new().parse(pattern), "Unable to compile pattern {}", pattern);
+    Ok(())
+}
+
+/// Check that every atom and call of a term can be built
+fn term_checker<T: AsRef<[u8]>>(term: &QueryTerm<T>, functions: &QueryFunctions) -> Result<(), SimpleError> {
     match term {
-        QueryTerm::Unary(atom) => atom_converter(atom),
-        QueryTerm::Binary(atom, op, term) => 
-            match op {
-                QueryOpTerm::AND => Box::new(ConjunctionConstraint::new(atom_converter(atom), term_converter(*term))),
-            }
+        QueryTerm::Unary(atom) => atom_checker(atom)?,
+        QueryTerm::Binary(factor, _, term) => {
+            term_checker(factor, functions)?;
+            term_checker(term, functions)?;
+        },
+        QueryTerm::Not(factor) => term_checker(factor, functions)?,
+        QueryTerm::Group(expression) => tree_checker(expression, functions)?,
+        QueryTerm::FullText(_) => {},
+        QueryTerm::Call(name, _) => if functions.get(text(name)).is_none() {
+            bail!("Unknown function {}", text(name))
+        },
+        QueryTerm::Reference(name) => bail!("Unknown saved query @{}", text(name)),
+    }
+    Ok(())
+}
+
+/// Check that every atom and call of a filter can be built, e.g. that its regular expressions
+/// are valid, before the optimizer folds any of them away
+pub fn tree_checker<T: AsRef<[u8]>>(tree: &QueryExpression<T>, functions: &QueryFunctions) -> Result<(), SimpleError> {
+    match tree {
+        QueryExpression::Unary(term) => term_checker(term, functions),
+        QueryExpression::Binary(term, _, expression) => {
+            term_checker(term, functions)?;
+            tree_checker(expression, functions)
+        },
     }
 }
 
-pub fn expr_converter(expr: QueryExpression<&[u8]>) -> Box<dyn Constraint> {
-    match expr {
-        QueryExpression::Unary(term) => term_converter(term),
-        QueryExpression::Binary(term, op, expr) => 
-            match op {
-                QueryOpExpression::OR => Box::new(DisjunctionConstraint::new(term_converter(term), expr_converter(*expr)))
-            }
+/// Append the instructions for a plan to a program
+fn plan_converter<T: AsRef<[u8]>>(plan: QueryPlan<T>, functions: &QueryFunctions, program: &mut Program) -> Result<(), SimpleError> {
+    match plan {
+        QueryPlan::Constant(value) => {
+            program.push(Instruction::Load(value));
+        },
+        QueryPlan::Atom(atom) => {
+            program.push(atom_converter(atom)?);
+        },
+        QueryPlan::FullText(full_text) => {
+            program.push(Instruction::Line(Box::new(FullTextConstraint::new(text(&full_text)))));
+        },
+        QueryPlan::Call(name, arguments) => {
+            let function = match functions.get(text(&name)) {
+                Some(function) => function,
+                None => bail!("Unknown function {}", text(&name)),
+            };
+            let arguments = arguments.iter().map(|argument| String::from(text(argument))).collect();
+            program.push(Instruction::Line(Box::new(FunctionConstraint::new(function, arguments))));
+        },
+        QueryPlan::Reference(name) => bail!("Unknown saved query @{}", text(&name)),
+        QueryPlan::Not(inner) => {
+            plan_converter(*inner, functions, program)?;
+            program.push(Instruction::Not);
+        },
+        QueryPlan::And(operands) => operands_converter(operands, functions, program, Instruction::JumpIfFalse)?,
+        QueryPlan::Or(operands) => operands_converter(operands, functions, program, Instruction::JumpIfTrue)?,
     }
+    Ok(())
 }
 
-/// Factory to construct a root constraint with appropriate subconstraints, given a parse tree
-pub fn constraint_factory(parse_tree: Query<&[u8]>) -> Box<dyn Constraint> {
-    expr_converter(parse_tree.tree)
-}
\ No newline at end of file
+/// Append the operands of a conjunction or disjunction, where `short_circuit` builds the jump
+/// past the remaining operands once the outcome is decided
+fn operands_converter<T: AsRef<[u8]>>(operands: Vec<QueryPlan<T>>, functions: &QueryFunctions, program: &mut Program, short_circuit: fn(usize) -> Instruction) -> Result<(), SimpleError> {
+    let mut jumps = Vec::with_capacity(operands.len());
+    let count = operands.len();
+    for (index, operand) in operands.into_iter().enumerate() {
+        plan_converter(operand, functions, program)?;
+        if index + 1 < count {
+            jumps.push(program.push(short_circuit(0)));
+        }
+    }
+    let end = program.position();
+    for jump in jumps {
+        program.patch_jump(jump, end);
+    }
+    Ok(())
+}
+
+/// Factory to compile an optimized query plan into a program
+///
+/// Fails if a constraint cannot be built from its atom, e.g. an invalid regular expression,
+/// or if a function is called that is not one of `functions`.
+pub fn program_factory<T: AsRef<[u8]>>(plan: QueryPlan<T>, functions: &QueryFunctions) -> Result<Program, SimpleError> {
+    let mut program = Program::new();
+    plan_converter(plan, functions, &mut program)?;
+    Ok(program)
+}
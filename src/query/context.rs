@@ -0,0 +1,39 @@
+//! Everything a query can refer to besides the fields of a log line
+use crate::query::functions::QueryFunctions;
+use crate::query::saved_queries::SavedQueries;
+use std::collections::HashMap;
+
+/// Functions, parameter values and saved queries to create queries with
+///
+/// References to saved queries are expanded first, so saved queries can contain placeholders
+/// and function calls of their own.
+#[derive(Clone, Default)]
+pub struct QueryContext {
+    pub functions: QueryFunctions,
+    pub parameters: HashMap<String, String>,
+    pub saved_queries: SavedQueries,
+}
+
+impl QueryContext {
+    pub fn new() -> Self {
+        QueryContext::default()
+    }
+
+    /// Use `functions` for calls like `is_internal_ip(client)`
+    pub fn functions(mut self, functions: QueryFunctions) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Use `parameters` as the values of placeholders like `$client`
+    pub fn parameters(mut self, parameters: HashMap<String, String>) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Use `saved_queries` for references like `@errors_in_dispatcher`
+    pub fn saved_queries(mut self, saved_queries: SavedQueries) -> Self {
+        self.saved_queries = saved_queries;
+        self
+    }
+}
@@ -0,0 +1,275 @@
+//! Evaluation of the expressions of `eval` stages against records
+use crate::constraint::compare_values;
+use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
+use crate::parser::log_line_parse_result::LogLineParseResult;
+use crate::parser::timestamp::Timestamp;
+use crate::query::constraint_factory::TIMESTAMP_KEY;
+use crate::query::pipeline::{Record, Stage};
+use crate::query::query_ast::{EvalAssignment, EvalExpression, EvalFunction, EvalOperator};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Value of an expression, which is only turned into text once it is stored in a field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Value of an absent field, or of an operation without a result like `int("abc")`
+    Missing,
+    Text(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl Value {
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Text(text) => text.parse().ok(),
+            Value::Number(number) => Some(*number),
+            Value::Missing | Value::Bool(_) => None,
+        }
+    }
+
+    /// Text a field is set to, if any
+    ///
+    /// Numbers that are not finite, like the result of a division by zero, have no text.
+    pub fn into_text(self) -> Option<String> {
+        match self {
+            Value::Text(text) => Some(text),
+            Value::Number(number) if number.is_finite() => Some(number.to_string()),
+            Value::Bool(value) => Some(value.to_string()),
+            Value::Number(_) | Value::Missing => None,
+        }
+    }
+
+    /// Whether the value counts as true in a condition
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Missing => false,
+            Value::Text(text) => !text.is_empty(),
+            Value::Number(number) => *number != 0.0,
+            Value::Bool(value) => *value,
+        }
+    }
+}
+
+fn from_number(number: Option<f64>) -> Value {
+    number.map_or(Value::Missing, Value::Number)
+}
+
+fn from_text(text: Option<String>) -> Value {
+    text.map_or(Value::Missing, Value::Text)
+}
+
+/// Characters of `text` from `start`, counting from the end if it is negative, on
+fn substring(text: &str, start: f64, length: Option<f64>) -> String {
+    let characters = text.chars().count() as i64;
+    let start = match start as i64 {
+        start if start < 0 => (characters + start).max(0),
+        start => start.min(characters),
+    };
+    let length = length.map_or(characters, |length| (length as i64).max(0));
+    text.chars().skip(start as usize).take(length as usize).collect()
+}
+
+fn call(function: EvalFunction, arguments: &[EvalExpression<String>], record: &dyn LogLineParseResult) -> Value {
+    // arguments are only evaluated once needed, so that `if` and `coalesce` skip the others
+    let argument = |index: usize| arguments.get(index).map_or(Value::Missing, |argument| evaluate(argument, record));
+    let number = |index: usize| argument(index).number();
+    let text = |index: usize| argument(index).into_text();
+    match function {
+        EvalFunction::Int => from_number(number(0).map(f64::trunc)),
+        EvalFunction::Num => from_number(number(0)),
+        EvalFunction::Round => {
+            let digits = if arguments.len() > 1 { number(1) } else { Some(0.0) };
+            match (number(0), digits) {
+                (Some(value), Some(digits)) => {
+                    let scale = 10_f64.powi(digits as i32);
+                    Value::Number((value * scale).round() / scale)
+                },
+                _ => Value::Missing,
+            }
+        },
+        EvalFunction::Abs => from_number(number(0).map(f64::abs)),
+        EvalFunction::Len => from_number(text(0).map(|text| text.chars().count() as f64)),
+        EvalFunction::Lower => from_text(text(0).map(|text| text.to_lowercase())),
+        EvalFunction::Upper => from_text(text(0).map(|text| text.to_uppercase())),
+        EvalFunction::Trim => from_text(text(0).map(|text| String::from(text.trim()))),
+        EvalFunction::Substr => match (text(0), number(1)) {
+            (Some(text), Some(start)) if arguments.len() > 2 => match number(2) {
+                Some(length) => Value::Text(substring(&text, start, Some(length))),
+                None => Value::Missing,
+            },
+            (Some(text), Some(start)) => Value::Text(substring(&text, start, None)),
+            _ => Value::Missing,
+        },
+        EvalFunction::Concat => Value::Text((0..arguments.len()).filter_map(text).collect()),
+        EvalFunction::If => if argument(0).is_true() { argument(1) } else { argument(2) },
+        EvalFunction::Coalesce => (0..arguments.len())
+            .map(argument)
+            .find(|value| *value != Value::Missing)
+            .unwrap_or(Value::Missing),
+    }
+}
+
+/// Apply an operator, where arithmetic needs numbers and comparisons compare like `<` in a filter
+fn binary(left: Value, operator: EvalOperator, right: Value) -> Value {
+    let arithmetic = |apply: fn(f64, f64) -> f64| match (left.number(), right.number()) {
+        (Some(left), Some(right)) => Value::Number(apply(left, right)),
+        _ => Value::Missing,
+    };
+    let comparison = |holds: fn(Ordering) -> bool| match (left.clone().into_text(), right.clone().into_text()) {
+        (Some(left), Some(right)) => Value::Bool(holds(compare_values(&left, &right))),
+        _ => Value::Missing,
+    };
+    match operator {
+        EvalOperator::Add => arithmetic(|left, right| left + right),
+        EvalOperator::Subtract => arithmetic(|left, right| left - right),
+        EvalOperator::Multiply => arithmetic(|left, right| left * right),
+        EvalOperator::Divide => arithmetic(|left, right| left / right),
+        EvalOperator::Remainder => arithmetic(|left, right| left % right),
+        EvalOperator::Equal => comparison(|ordering| ordering == Ordering::Equal),
+        EvalOperator::NotEqual => comparison(|ordering| ordering != Ordering::Equal),
+        EvalOperator::Less => comparison(|ordering| ordering == Ordering::Less),
+        EvalOperator::LessEqual => comparison(|ordering| ordering != Ordering::Greater),
+        EvalOperator::Greater => comparison(|ordering| ordering == Ordering::Greater),
+        EvalOperator::GreaterEqual => comparison(|ordering| ordering != Ordering::Less),
+    }
+}
+
+/// Compute the value of an expression for a record
+///
+/// Operations on missing values, or on text that is not a number where a number is needed,
+/// result in a missing value rather than an error.
+pub fn evaluate(expression: &EvalExpression<String>, record: &dyn LogLineParseResult) -> Value {
+    match expression {
+        EvalExpression::Field(field) if field == TIMESTAMP_KEY => from_text(record.get_timestamp().map(|timestamp| timestamp.to_string())),
+        EvalExpression::Field(field) => from_text(record.get_field(field).cloned()),
+        EvalExpression::Text(text) => Value::Text(text.clone()),
+        EvalExpression::Number(number) => from_number(number.parse().ok()),
+        EvalExpression::Call(function, arguments) => call(*function, arguments, record),
+        EvalExpression::Binary(left, operator, right) => binary(evaluate(left, record), *operator, evaluate(right, record)),
+    }
+}
+
+/// Sets fields to the values of expressions, where each expression sees the fields set before it
+///
+/// A field whose expression has no value is removed from the record.
+pub struct EvalStage {
+    assignments: Vec<EvalAssignment<String>>,
+}
+
+impl EvalStage {
+    pub fn new(assignments: Vec<EvalAssignment<String>>) -> Self {
+        EvalStage {
+            assignments,
+        }
+    }
+}
+
+/// Fields of a record while an `eval` stage assigns them, so that the record is only built once
+struct Assigned {
+    content: HashMap<String, String>,
+    timestamp: Option<Timestamp>,
+}
+
+impl LogLineParseResult for Assigned {
+    fn get_content(&self) -> &HashMap<String, String> {
+        &self.content
+    }
+
+    fn get_field(&self, field: &str) -> Option<&String> {
+        self.content.get(field)
+    }
+
+    fn get_timestamp(&self) -> Option<&Timestamp> {
+        self.timestamp.as_ref()
+    }
+}
+
+impl Stage for EvalStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        let mut assigned = Assigned {
+            content: record.get_content().clone(),
+            timestamp: record.get_timestamp().cloned(),
+        };
+        for assignment in self.assignments.iter() {
+            match evaluate(&assignment.expression, &assigned).into_text() {
+                Some(value) => assigned.content.insert(assignment.field.clone(), value),
+                None => assigned.content.remove(&assignment.field),
+            };
+        }
+        Some(Box::new(DefaultLogLineParseResult::with_timestamp(assigned.content, assigned.timestamp)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::query_ast::parse_query;
+    use crate::query::query_ast::QueryStage;
+    use std::str::from_utf8;
+
+    fn record(fields: &[(&str, &str)]) -> Record {
+        Box::new(DefaultLogLineParseResult::new(
+            fields.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+        ))
+    }
+
+    /// Parse the assignments of an `eval` stage
+    fn assignments(raw: &str) -> Vec<EvalAssignment<String>> {
+        match parse_query(&format!("a=\"x\" | eval {}", raw)).unwrap().stages.pop().unwrap() {
+            QueryStage::Eval(assignments) => assignments.into_iter().map(|assignment| EvalAssignment {
+                field: String::from(from_utf8(assignment.field).unwrap()),
+                expression: assignment.expression.map(&|text| String::from(from_utf8(text).unwrap())),
+            }).collect(),
+            stage => panic!("Not an eval stage: {:?}", stage),
+        }
+    }
+
+    fn evaluated(raw: &str, record: &dyn LogLineParseResult) -> Option<String> {
+        evaluate(&assignments(&format!("result = {}", raw))[0].expression, record).into_text()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let log_line = record(&[("latency", "12.7"), ("class", "ImageManagerImpl"), ("user", " Bob ")]);
+        let cases = [
+            ("int(latency) * 1000", Some("12000")),
+            ("latency * 2 + 1", Some("26.4")),
+            ("10 - 4 - 3", Some("3")),
+            ("2 * (3 + 4) % 5", Some("4")),
+            ("round(latency)", Some("13")),
+            ("round(latency / 3, 2)", Some("4.23")),
+            ("abs(-2.5)", Some("2.5")),
+            ("1 / 0", None),
+            ("class * 2", None),
+            ("substr(class, 0, 5)", Some("Image")),
+            ("substr(class, -4)", Some("Impl")),
+            ("substr(class, 100)", Some("")),
+            ("len(class)", Some("16")),
+            ("upper(trim(user))", Some("BOB")),
+            ("concat(lower(class), \"-\", missing, latency)", Some("imagemanagerimpl-12.7")),
+            ("latency > 9", Some("true")),
+            ("class == \"Other\"", Some("false")),
+            ("if(latency >= 10, \"slow\", \"fast\")", Some("slow")),
+            ("if(missing > 10, \"slow\", \"fast\")", Some("fast")),
+            ("coalesce(missing, int(class), user)", Some(" Bob ")),
+            ("missing + 1", None),
+        ];
+        for (raw, expected) in cases.iter() {
+            assert_eq!(evaluated(raw, log_line.as_ref()).as_deref(), *expected, "Evaluating {}", raw);
+        }
+    }
+
+    #[test]
+    fn test_eval_stage() {
+        let mut stage = EvalStage::new(assignments("ms = int(latency) * 1000, slow = ms > 5000, latency = missing"));
+        let processed = stage.process(record(&[("latency", "7"), ("class", "A")])).unwrap();
+        assert_eq!(
+            *processed.get_content(),
+            [("ms", "7000"), ("slow", "true"), ("class", "A")].iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value)))
+                .collect::<HashMap<String, String>>(),
+            "Later assignments see earlier ones, and fields without a value are removed"
+        );
+    }
+}
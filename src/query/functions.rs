@@ -0,0 +1,39 @@
+//! Predicates that library users register by name so that queries can call them
+use crate::parser::log_line_parse_result::LogLineParseResult;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A registered predicate, called with a log line and the arguments of the call as written
+pub type QueryFunction = Rc<dyn Fn(&dyn LogLineParseResult, &[String]) -> bool>;
+
+/// Named predicates that a query can call like `is_internal_ip(client)`
+///
+/// The arguments of a call are passed as they were written, so a function is free to treat
+/// them as keys to look up, like `client`, or as values, like `"10.0.0.0/8"`.
+#[derive(Clone, Default)]
+pub struct QueryFunctions {
+    functions: HashMap<String, QueryFunction>,
+}
+
+impl QueryFunctions {
+    pub fn new() -> Self {
+        QueryFunctions {
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Register `function` under `name`, replacing any function registered under it before
+    ///
+    /// `exists` and `missing` always check for a field, so functions with those names are
+    /// never called.
+    pub fn register<F>(mut self, name: &str, function: F) -> Self
+        where F: Fn(&dyn LogLineParseResult, &[String]) -> bool + 'static {
+        self.functions.insert(String::from(name), Rc::new(function));
+        self
+    }
+
+    /// The function registered under `name`, if any
+    pub fn get(&self, name: &str) -> Option<QueryFunction> {
+        self.functions.get(name).cloned()
+    }
+}
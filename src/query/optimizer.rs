@@ -0,0 +1,212 @@
+use crate::query::query_ast::{QueryAtom, QueryConstraint, QueryValue, QueryExpression, QueryTerm, QueryOpTerm, QueryOpExpression};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// A simplified query, where chains of the same connective are flattened into n-ary nodes
+pub enum QueryPlan<T> {
+    Constant(bool),
+    Atom(QueryAtom<T>),
+    FullText(T),
+    Call(T, Vec<T>),
+    /// Saved query that was not expanded, which fails to build
+    Reference(T),
+    Not(Box<QueryPlan<T>>),
+    And(Vec<QueryPlan<T>>),
+    Or(Vec<QueryPlan<T>>),
+}
+
+/// Lower a term into a plan, collecting the operands of a conjunction into one node
+fn term_to_plan<T>(term: QueryTerm<T>) -> QueryPlan<T> {
+    match term {
+        QueryTerm::Unary(atom) => QueryPlan::Atom(atom),
+        QueryTerm::Binary(factor, op, term) =>
+            match op {
+                QueryOpTerm::AND => QueryPlan::And(vec![term_to_plan(*factor), term_to_plan(*term)]),
+            }
+        QueryTerm::Not(factor) => QueryPlan::Not(Box::new(term_to_plan(*factor))),
+        QueryTerm::Group(expr) => expr_to_plan(*expr),
+        QueryTerm::FullText(text) => QueryPlan::FullText(text),
+        QueryTerm::Call(name, arguments) => QueryPlan::Call(name, arguments),
+        QueryTerm::Reference(name) => QueryPlan::Reference(name),
+    }
+}
+
+/// Lower an expression into a plan
+fn expr_to_plan<T>(expr: QueryExpression<T>) -> QueryPlan<T> {
+    match expr {
+        QueryExpression::Unary(term) => term_to_plan(term),
+        QueryExpression::Binary(term, op, expr) =>
+            match op {
+                QueryOpExpression::OR => QueryPlan::Or(vec![term_to_plan(term), expr_to_plan(*expr)]),
+            }
+    }
+}
+
+/// Rough relative cost of checking an atom against a log line
+fn atom_cost<T>(atom: &QueryAtom<T>) -> u8 {
+    match atom.query_constraint {
+        QueryConstraint::EXISTS | QueryConstraint::MISSING => 1,
+        QueryConstraint::EQ | QueryConstraint::NE | QueryConstraint::IN | QueryConstraint::NOTIN => 2,
+        QueryConstraint::IEQ | QueryConstraint::LT | QueryConstraint::LE | QueryConstraint::GT | QueryConstraint::GE => 3,
+        QueryConstraint::CONTAINS | QueryConstraint::STARTSWITH | QueryConstraint::ENDSWITH
+            | QueryConstraint::ICONTAINS | QueryConstraint::ISTARTSWITH | QueryConstraint::IENDSWITH => 4,
+        QueryConstraint::GLOB | QueryConstraint::MATCH | QueryConstraint::NOTMATCH => 5,
+    }
+}
+
+/// Rough relative cost of checking a plan, used to check cheap operands first
+fn cost<T>(plan: &QueryPlan<T>) -> u8 {
+    match plan {
+        QueryPlan::Constant(_) => 0,
+        QueryPlan::Atom(atom) => atom_cost(atom),
+        // full text searches scan every captured field
+        QueryPlan::FullText(_) => 6,
+        // registered functions may do anything, so they go last
+        QueryPlan::Call(..) => 7,
+        QueryPlan::Reference(_) => 0,
+        QueryPlan::Not(inner) => cost(inner),
+        QueryPlan::And(operands) | QueryPlan::Or(operands) => operands.iter().map(cost).max().unwrap_or(0),
+    }
+}
+
+/// Replace atoms whose outcome does not depend on the log line, or that have a cheaper equivalent
+fn simplify_atom<T>(atom: QueryAtom<T>) -> QueryPlan<T> {
+    match (atom.query_constraint, atom.query_value) {
+        (QueryConstraint::IN, QueryValue::List(ref values)) if values.is_empty() => QueryPlan::Constant(false),
+        // a missing field fails every operator but `missing`, so this only checks for presence
+        (QueryConstraint::NOTIN, QueryValue::List(ref values)) if values.is_empty() => QueryPlan::Atom(QueryAtom {
+            query_key: atom.query_key,
+            query_constraint: QueryConstraint::EXISTS,
+            query_value: QueryValue::Empty,
+        }),
+        (query_constraint, query_value) => QueryPlan::Atom(QueryAtom { query_key: atom.query_key, query_constraint, query_value }),
+    }
+}
+
+fn simplify_not<T: PartialEq>(inner: QueryPlan<T>) -> QueryPlan<T> {
+    match simplify(inner) {
+        QueryPlan::Constant(value) => QueryPlan::Constant(!value),
+        QueryPlan::Not(inner) => *inner,
+        inner => QueryPlan::Not(Box::new(inner)),
+    }
+}
+
+/// Simplify the operands of a conjunction (`absorbing == false`) or disjunction (`absorbing == true`)
+///
+/// `absorbing` is the constant that decides the connective on its own, while its negation
+/// can be dropped from the operands.
+fn simplify_connective<T: PartialEq>(operands: Vec<QueryPlan<T>>, absorbing: bool) -> QueryPlan<T> {
+    let mut simplified: Vec<QueryPlan<T>> = Vec::with_capacity(operands.len());
+    let mut pending = operands;
+    pending.reverse();
+    while let Some(operand) = pending.pop() {
+        match (simplify(operand), absorbing) {
+            (QueryPlan::Constant(value), _) if value == absorbing => return QueryPlan::Constant(absorbing),
+            (QueryPlan::Constant(_), _) => {},
+            // flatten nested operands of the same connective in place
+            (QueryPlan::And(nested), false) | (QueryPlan::Or(nested), true) => pending.extend(nested.into_iter().rev()),
+            (operand, _) => {
+                let complemented = simplified.iter().any(|other| match (other, &operand) {
+                    (QueryPlan::Not(inner), operand) | (operand, QueryPlan::Not(inner)) => **inner == *operand,
+                    _ => false,
+                });
+                if complemented {
+                    // `x && !x` never holds and `x || !x` always does
+                    return QueryPlan::Constant(absorbing)
+                }
+                if !simplified.contains(&operand) {
+                    simplified.push(operand);
+                }
+            },
+        }
+    }
+    simplified.sort_by_key(cost);
+    match simplified.len() {
+        0 => QueryPlan::Constant(!absorbing),
+        1 => simplified.pop().unwrap(),
+        _ if absorbing => QueryPlan::Or(simplified),
+        _ => QueryPlan::And(simplified),
+    }
+}
+
+/// Simplify a plan bottom up
+fn simplify<T: PartialEq>(plan: QueryPlan<T>) -> QueryPlan<T> {
+    match plan {
+        QueryPlan::Atom(atom) => simplify_atom(atom),
+        QueryPlan::Not(inner) => simplify_not(*inner),
+        QueryPlan::And(operands) => simplify_connective(operands, false),
+        QueryPlan::Or(operands) => simplify_connective(operands, true),
+        plan => plan,
+    }
+}
+
+/// Turn the filter of a parse tree into an equivalent plan that is cheaper to check
+///
+/// Constant operands are folded away, nested conjunctions and disjunctions are flattened,
+/// duplicate operands are removed and cheap checks are moved ahead of expensive ones.
+/// Registered functions are assumed to give the same result when called twice on a log line.
+/// Operands that are folded away are never built, so the tree should be checked with
+/// `tree_checker` first.
+pub fn optimize<T: PartialEq>(tree: QueryExpression<T>) -> QueryPlan<T> {
+    simplify(expr_to_plan(tree))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::query_ast::parse_query;
+
+    fn atom<'a>(key: &'a str, query_constraint: QueryConstraint, value: &'a str) -> QueryPlan<&'a [u8]> {
+        QueryPlan::Atom(QueryAtom {query_key: key.as_bytes(), query_constraint, query_value: QueryValue::Literal(value.as_bytes())})
+    }
+
+    fn optimized(raw: &str) -> QueryPlan<&[u8]> {
+        optimize(parse_query(raw).unwrap().tree)
+    }
+
+    #[test]
+    fn test_flatten_and_dedupe() {
+        assert_eq!(
+            optimized("a=\"1\" && (b=\"2\" && (a=\"1\" && c=\"3\"))"),
+            QueryPlan::And(vec![
+                atom("a", QueryConstraint::EQ, "1"),
+                atom("b", QueryConstraint::EQ, "2"),
+                atom("c", QueryConstraint::EQ, "3"),
+            ])
+        );
+        assert_eq!(
+            optimized("a=\"1\" || (b=\"2\" || a=\"1\") || a=\"1\""),
+            QueryPlan::Or(vec![atom("a", QueryConstraint::EQ, "1"), atom("b", QueryConstraint::EQ, "2")])
+        );
+        assert_eq!(optimized("(a=\"1\" && a=\"1\")"), atom("a", QueryConstraint::EQ, "1"));
+        assert_eq!(optimized("!!a=\"1\""), atom("a", QueryConstraint::EQ, "1"));
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(optimized("a in ()"), QueryPlan::Constant(false));
+        assert_eq!(optimized("!a in ()"), QueryPlan::Constant(true));
+        assert_eq!(optimized("b=\"2\" && a in ()"), QueryPlan::Constant(false));
+        assert_eq!(optimized("b=\"2\" || !a in ()"), QueryPlan::Constant(true));
+        assert_eq!(optimized("b=\"2\" || a in ()"), atom("b", QueryConstraint::EQ, "2"));
+        assert_eq!(optimized("b=\"2\" && !b=\"2\""), QueryPlan::Constant(false));
+        assert_eq!(optimized("!b=\"2\" || c=\"3\" || b=\"2\""), QueryPlan::Constant(true));
+        assert_eq!(
+            optimized("a not in ()"),
+            QueryPlan::Atom(QueryAtom {query_key: "a".as_bytes(), query_constraint: QueryConstraint::EXISTS, query_value: QueryValue::Empty})
+        );
+    }
+
+    #[test]
+    fn test_cheap_checks_first() {
+        assert_eq!(
+            optimized("content~\"time.*out\" && \"error\" && class=*\"*Impl\" && thread=\"main\" && content contains \"x\""),
+            QueryPlan::And(vec![
+                atom("thread", QueryConstraint::EQ, "main"),
+                atom("content", QueryConstraint::CONTAINS, "x"),
+                atom("content", QueryConstraint::MATCH, "time.*out"),
+                atom("class", QueryConstraint::GLOB, "*Impl"),
+                QueryPlan::FullText("error".as_bytes()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Errors for queries that do not match the query grammar
+use std::error::Error;
+use std::fmt;
+
+/// What the query parser was looking for when it failed
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Expected {
+    /// A specific character
+    Token(u8),
+    /// A grammar element, like an operator or a value
+    Element(&'static str),
+}
+
+impl From<chomp::parsers::Error<u8>> for Expected {
+    fn from(error: chomp::parsers::Error<u8>) -> Self {
+        match error.expected_token() {
+            Some(token) => Expected::Token(*token),
+            None => Expected::Element("a different character"),
+        }
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "'{}'", *token as char),
+            Expected::Element(element) => write!(f, "{}", element),
+        }
+    }
+}
+
+/// A query that could not be parsed, along with where parsing stopped
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct QueryParseError {
+    pub query: String,
+    /// Byte offset into the query at which parsing failed
+    pub offset: usize,
+    pub expected: Expected,
+}
+
+impl QueryParseError {
+    pub fn new(query: &str, offset: usize, expected: Expected) -> Self {
+        QueryParseError {
+            query: String::from(query),
+            offset,
+            expected,
+        }
+    }
+
+    /// Render the query with a caret pointing at the position parsing failed at
+    pub fn caret(&self) -> String {
+        let column = match self.query.get(..self.offset) {
+            Some(prefix) => prefix.chars().count(),
+            None => self.offset,
+        };
+        format!("{}\n{}^", self.query, " ".repeat(column))
+    }
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unable to parse query, expected {} at offset {}\n{}", self.expected, self.offset, self.caret())
+    }
+}
+
+impl Error for QueryParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_rendering() {
+        let error = QueryParseError::new("class=\"A\" && thread", 19, Expected::Element("operator"));
+        assert_eq!(
+            error.to_string(),
+            "Unable to parse query, expected operator at offset 19\nclass=\"A\" && thread\n                   ^"
+        );
+        let error = QueryParseError::new("(a=\"b\"", 6, Expected::Token(b')'));
+        assert_eq!(error.caret(), "(a=\"b\"\n      ^");
+        assert_eq!(error.expected.to_string(), "')'");
+    }
+}
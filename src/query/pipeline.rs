@@ -0,0 +1,274 @@
+//! Stages that log lines passing the filter of a query go through before they are output
+use crate::constraint::compare_values;
+use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
+use crate::parser::log_line_parse_result::LogLineParseResult;
+use crate::query::constraint_factory::TIMESTAMP_KEY;
+use crate::query::eval::EvalStage;
+use crate::query::query_ast::{QueryStage, SortKey};
+use crate::query::stats::{StatsStage, TimechartStage};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+
+/// A log line, or a row derived from log lines, flowing through a pipeline
+pub type Record = Box<dyn LogLineParseResult>;
+
+/// A single step of a pipeline
+pub trait Stage {
+    /// Take in a record, returning the record to pass on to the next stage, if any
+    fn process(&mut self, record: Record) -> Option<Record>;
+
+    /// Called once all records were processed, returning records the stage held back
+    fn finish(&mut self) -> Vec<Record> {
+        Vec::new()
+    }
+
+    /// Whether the stage drops every record from now on
+    fn is_done(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps only some fields of each record
+pub struct FieldsStage {
+    fields: Vec<String>,
+}
+
+impl Stage for FieldsStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        let content: HashMap<String, String> = self.fields.iter()
+            .filter_map(|field| record.get_field(field).map(|value| (field.clone(), value.clone())))
+            .collect();
+        // the timestamp is kept so that later stages can still sort by it
+        Some(Box::new(DefaultLogLineParseResult::with_timestamp(content, record.get_timestamp().cloned())))
+    }
+}
+
+/// Compare two values of a sort key, ordering records without the key last
+fn compare_present<T, F: Fn(&T, &T) -> Ordering>(left: Option<T>, right: Option<T>, descending: bool, compare: F) -> Ordering {
+    match (left, right) {
+        (Some(left), Some(right)) if descending => compare(&right, &left),
+        (Some(left), Some(right)) => compare(&left, &right),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Compare two records by a list of sort keys
+fn compare_records(left: &dyn LogLineParseResult, right: &dyn LogLineParseResult, keys: &[SortKey<String>]) -> Ordering {
+    for sort_key in keys {
+        let ordering = if sort_key.key == TIMESTAMP_KEY {
+            compare_present(left.get_timestamp(), right.get_timestamp(), sort_key.descending, |left, right| left.cmp(right))
+        } else {
+            compare_present(left.get_field(&sort_key.key), right.get_field(&sort_key.key), sort_key.descending, |left, right| compare_values(left, right))
+        };
+        if ordering != Ordering::Equal {
+            return ordering
+        }
+    }
+    Ordering::Equal
+}
+
+/// Holds back every record and releases them in order once all were seen
+pub struct SortStage {
+    keys: Vec<SortKey<String>>,
+    records: Vec<Record>,
+}
+
+impl Stage for SortStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        self.records.push(record);
+        None
+    }
+
+    fn finish(&mut self) -> Vec<Record> {
+        let mut records = std::mem::take(&mut self.records);
+        let keys = &self.keys;
+        records.sort_by(|left, right| compare_records(left.as_ref(), right.as_ref(), keys));
+        records
+    }
+}
+
+/// Passes on the first records and drops the rest
+pub struct HeadStage {
+    remaining: usize,
+}
+
+impl Stage for HeadStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        if self.remaining == 0 {
+            return None
+        }
+        self.remaining -= 1;
+        Some(record)
+    }
+
+    fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Holds back the last records seen and releases them once all were seen
+pub struct TailStage {
+    count: usize,
+    records: VecDeque<Record>,
+}
+
+impl Stage for TailStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        if self.count > 0 {
+            if self.records.len() == self.count {
+                self.records.pop_front();
+            }
+            self.records.push_back(record);
+        }
+        None
+    }
+
+    fn finish(&mut self) -> Vec<Record> {
+        self.records.drain(..).collect()
+    }
+}
+
+/// Build the stage described by a parsed stage
+pub fn stage_factory(stage: &QueryStage<String>) -> Box<dyn Stage> {
+    match stage {
+        QueryStage::Fields(fields) => Box::new(FieldsStage { fields: fields.clone() }),
+        QueryStage::Sort(keys) => Box::new(SortStage { keys: keys.clone(), records: Vec::new() }),
+        QueryStage::Head(count) => Box::new(HeadStage { remaining: *count }),
+        QueryStage::Tail(count) => Box::new(TailStage { count: *count, records: VecDeque::new() }),
+        QueryStage::Stats { aggregates, by } => Box::new(StatsStage::new(aggregates.clone(), by.clone())),
+        // Spans too long for milliseconds are rejected when the query is parsed
+        QueryStage::Timechart { span, aggregates, by } => Box::new(TimechartStage::new(span.milliseconds().unwrap_or(i64::MAX), aggregates.clone(), by.clone())),
+        QueryStage::Eval(assignments) => Box::new(EvalStage::new(assignments.clone())),
+    }
+}
+
+/// Records passing the filter of a query go through each stage in turn before being emitted
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline {
+            stages,
+        }
+    }
+
+    /// Feed a record into the pipeline, passing any record that makes it through to `emit`
+    pub fn push(&mut self, record: Record, emit: &mut dyn FnMut(Record)) {
+        self.push_from(0, record, emit)
+    }
+
+    fn push_from(&mut self, first: usize, record: Record, emit: &mut dyn FnMut(Record)) {
+        let mut record = record;
+        for stage in self.stages[first..].iter_mut() {
+            record = match stage.process(record) {
+                Some(record) => record,
+                None => return,
+            };
+        }
+        emit(record)
+    }
+
+    /// Flush the records held back by each stage through the stages after it
+    pub fn finish(&mut self, emit: &mut dyn FnMut(Record)) {
+        for index in 0..self.stages.len() {
+            for record in self.stages[index].finish() {
+                self.push_from(index + 1, record, emit);
+            }
+        }
+    }
+
+    /// Whether no further record pushed into the pipeline can be emitted, so input can stop early
+    pub fn is_done(&self) -> bool {
+        self.stages.iter().any(|stage| stage.is_done())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::timestamp::Timestamp;
+
+    fn record(class: &str, latency: Option<&str>) -> Record {
+        let mut content = HashMap::new();
+        content.insert(String::from("class"), String::from(class));
+        if let Some(latency) = latency {
+            content.insert(String::from("latency"), String::from(latency));
+        }
+        Box::new(DefaultLogLineParseResult::new(content))
+    }
+
+    fn run(stages: Vec<QueryStage<String>>, records: Vec<Record>) -> Vec<Record> {
+        let mut pipeline = Pipeline::new(stages.iter().map(stage_factory).collect());
+        let mut emitted = Vec::new();
+        for record in records {
+            pipeline.push(record, &mut |record| emitted.push(record));
+        }
+        pipeline.finish(&mut |record| emitted.push(record));
+        emitted
+    }
+
+    fn classes(records: &[Record]) -> Vec<&str> {
+        records.iter().map(|record| record.get_field("class").unwrap().as_str()).collect()
+    }
+
+    fn sort_key(key: &str, descending: bool) -> SortKey<String> {
+        SortKey { key: String::from(key), descending }
+    }
+
+    #[test]
+    fn test_fields_stage() {
+        let emitted = run(vec![QueryStage::Fields(vec![String::from("latency"), String::from("user")])], vec![record("A", Some("5"))]);
+        assert_eq!(
+            *emitted[0].get_content(),
+            [(String::from("latency"), String::from("5"))].iter().cloned().collect::<HashMap<String, String>>()
+        );
+    }
+
+    #[test]
+    fn test_sort_stage() {
+        let records = || vec![record("A", Some("50")), record("B", None), record("C", Some("9")), record("D", Some("50"))];
+        let emitted = run(vec![QueryStage::Sort(vec![sort_key("latency", false)])], records());
+        assert_eq!(classes(&emitted), ["C", "A", "D", "B"], "Numbers sort numerically, missing fields last and ties stay in order");
+        let emitted = run(vec![QueryStage::Sort(vec![sort_key("latency", true), sort_key("class", true)])], records());
+        assert_eq!(classes(&emitted), ["D", "A", "C", "B"]);
+    }
+
+    #[test]
+    fn test_sort_stage_by_timestamp() {
+        let at = |class: &str, time: &str| -> Record {
+            let content = [(String::from("class"), String::from(class))].iter().cloned().collect();
+            Box::new(DefaultLogLineParseResult::with_timestamp(content, time.parse::<Timestamp>().ok()))
+        };
+        let emitted = run(
+            vec![QueryStage::Fields(vec![String::from("class")]), QueryStage::Sort(vec![sort_key("@time", true)])],
+            vec![at("A", "2020/07/17 23:00"), at("B", "2020/07/18"), at("C", "2020/07/17 23:30")],
+        );
+        assert_eq!(classes(&emitted), ["B", "C", "A"]);
+    }
+
+    #[test]
+    fn test_head_and_tail_stages() {
+        let records = || vec![record("A", None), record("B", None), record("C", None), record("D", None)];
+        assert_eq!(classes(&run(vec![QueryStage::Head(2)], records())), ["A", "B"]);
+        assert_eq!(classes(&run(vec![QueryStage::Tail(2)], records())), ["C", "D"]);
+        assert_eq!(classes(&run(vec![QueryStage::Tail(0)], records())), Vec::<&str>::new());
+        assert_eq!(classes(&run(vec![QueryStage::Tail(3), QueryStage::Head(1)], records())), ["B"]);
+        assert_eq!(classes(&run(vec![QueryStage::Tail(usize::MAX)], records())), ["A", "B", "C", "D"], "Nothing is allocated up front");
+        assert_eq!(
+            classes(&run(vec![QueryStage::Sort(vec![sort_key("class", true)]), QueryStage::Head(1)], records())),
+            ["D"]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_is_done() {
+        let mut pipeline = Pipeline::new(vec![stage_factory(&QueryStage::Head(1))]);
+        assert_eq!(pipeline.is_done(), false);
+        pipeline.push(record("A", None), &mut |_| {});
+        assert_eq!(pipeline.is_done(), true);
+    }
+}
@@ -0,0 +1,244 @@
+//! Flat, slot-resolved form of a query that log lines are checked against
+use crate::constraint::{Constraint, FieldComparisonConstraint, FieldConstraint};
+use crate::parser::log_line_parse_result::LogLineParseResult;
+use std::rc::Rc;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// How a field is found in a log line
+pub enum FieldRef {
+    /// Look the field up by name, for log lines of unknown origin
+    Name(String),
+    /// Position of the field in the field names the program was resolved against
+    Slot(String, usize),
+    /// The field names the program was resolved against do not include this field
+    Absent(String),
+}
+
+/// A single step of a program, which updates or branches on the result register
+pub enum Instruction {
+    /// Set the result to a constant
+    Load(bool),
+    /// Set the result to the outcome of a constraint on one field
+    Field(FieldRef, Box<dyn FieldConstraint>),
+    /// Set the result to the outcome of a constraint between two fields
+    Compare(FieldRef, FieldRef, FieldComparisonConstraint),
+    /// Set the result to the outcome of a constraint on the whole log line
+    Line(Box<dyn Constraint>),
+    /// Invert the result
+    Not,
+    /// Continue at the given instruction if the result is false
+    JumpIfFalse(usize),
+    /// Continue at the given instruction if the result is true
+    JumpIfTrue(usize),
+}
+
+/// Refer to a field by its slot in `field_names` instead of its name
+fn resolve_field(field: &mut FieldRef, field_names: &[String]) {
+    let name = match field {
+        FieldRef::Name(name) | FieldRef::Slot(name, _) | FieldRef::Absent(name) => std::mem::take(name),
+    };
+    *field = match field_names.iter().position(|field_name| *field_name == name) {
+        Some(slot) => FieldRef::Slot(name, slot),
+        None => FieldRef::Absent(name),
+    };
+}
+
+/// Value of a field of a log line, where `slots` are the values of the log line if it has the
+/// field names the program was resolved against
+fn field_value<'a>(field: &FieldRef, log_line: &'a dyn LogLineParseResult, slots: Option<&'a [Option<String>]>) -> Option<&'a String> {
+    match (field, slots) {
+        (FieldRef::Slot(_, slot), Some(slots)) => slots.get(*slot)?.as_ref(),
+        (FieldRef::Absent(_), Some(_)) => None,
+        (FieldRef::Name(name), _) | (FieldRef::Slot(name, _), None) | (FieldRef::Absent(name), None) => log_line.get_field(name),
+    }
+}
+
+/// A query compiled to a flat list of instructions
+///
+/// The result register starts out true, so an empty program passes every log line.
+/// Conjunctions and disjunctions short circuit by jumping past their remaining operands.
+#[derive(Default)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    /// Field names the fields of the instructions were resolved against, if any
+    field_names: Option<Rc<[String]>>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the next pushed instruction will have
+    pub fn position(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Append an instruction, returning its index
+    pub fn push(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    /// Point a previously pushed jump at `target`
+    pub fn patch_jump(&mut self, jump: usize, target: usize) {
+        match &mut self.instructions[jump] {
+            Instruction::JumpIfFalse(destination) | Instruction::JumpIfTrue(destination) => *destination = target,
+            _ => panic!("Instruction {} is not a jump", jump),
+        }
+    }
+
+    /// Additionally require a constraint on the whole log line to hold
+    pub fn and_line(&mut self, constraint: Box<dyn Constraint>) {
+        if !self.instructions.is_empty() {
+            let end = self.position() + 2;
+            self.push(Instruction::JumpIfFalse(end));
+        }
+        self.push(Instruction::Line(constraint));
+    }
+
+    /// Resolve fields referred to by name to their slot in `field_names`
+    ///
+    /// Slots are only used for log lines that share these very field names, as the lines of
+    /// the same parser do. Fields of other log lines are still looked up by name.
+    pub fn resolve_fields(&mut self, field_names: Rc<[String]>) {
+        for instruction in self.instructions.iter_mut() {
+            match instruction {
+                Instruction::Field(field, _) => resolve_field(field, &field_names),
+                Instruction::Compare(field, other, _) => {
+                    resolve_field(field, &field_names);
+                    resolve_field(other, &field_names);
+                },
+                _ => {},
+            }
+        }
+        self.field_names = Some(field_names);
+    }
+
+    /// Run the program against a log line
+    pub fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
+        let slots = match &self.field_names {
+            Some(field_names) if log_line.get_field_names().is_some_and(|line_field_names| Rc::ptr_eq(field_names, line_field_names)) => log_line.get_slots(),
+            _ => None,
+        };
+        let mut result = true;
+        let mut next = 0;
+        while let Some(instruction) = self.instructions.get(next) {
+            next += 1;
+            match instruction {
+                Instruction::Load(value) => result = *value,
+                Instruction::Field(field, constraint) => {
+                    result = match field_value(field, log_line, slots) {
+                        Some(value) => constraint.check_value(value),
+                        None => constraint.check_absent(),
+                    };
+                },
+                Instruction::Compare(field, other, constraint) => {
+                    result = match (field_value(field, log_line, slots), field_value(other, log_line, slots)) {
+                        (Some(value), Some(other_value)) => constraint.check_values(value, other_value),
+                        _ => false,
+                    };
+                },
+                Instruction::Line(constraint) => result = constraint.check(log_line),
+                Instruction::Not => result = !result,
+                Instruction::JumpIfFalse(target) => if !result {
+                    next = *target
+                },
+                Instruction::JumpIfTrue(target) => if result {
+                    next = *target
+                },
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constraint::{SimpleEqualityConstraint, ExistenceConstraint, FieldRelation};
+    use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
+
+    fn field_names() -> Vec<String> {
+        vec![String::from("class"), String::from("thread")]
+    }
+
+    fn log_line(class: &str) -> DefaultLogLineParseResult {
+        DefaultLogLineParseResult::from_slots(field_names().into(), vec![Some(String::from(class)), None], None)
+    }
+
+    fn equals(field: &str, value: &str) -> Instruction {
+        Instruction::Field(FieldRef::Name(String::from(field)), Box::new(SimpleEqualityConstraint::new(field, value)))
+    }
+
+    #[test]
+    fn test_empty_program() {
+        assert_eq!(Program::new().check(&log_line("A")), true);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // class="A" || (class="B" && !exists(thread))
+        let mut program = Program::new();
+        program.push(equals("class", "A"));
+        let or_jump = program.push(Instruction::JumpIfTrue(0));
+        program.push(equals("class", "B"));
+        let and_jump = program.push(Instruction::JumpIfFalse(0));
+        program.push(Instruction::Field(FieldRef::Name(String::from("thread")), Box::new(ExistenceConstraint::new("thread", false))));
+        program.push(Instruction::Not);
+        let end = program.position();
+        program.patch_jump(or_jump, end);
+        program.patch_jump(and_jump, end);
+
+        assert_eq!(program.check(&log_line("A")), true);
+        assert_eq!(program.check(&log_line("B")), true);
+        assert_eq!(program.check(&log_line("C")), false);
+
+        program.and_line(Box::new(ExistenceConstraint::new("user", false)));
+        assert_eq!(program.check(&log_line("A")), false);
+    }
+
+    #[test]
+    fn test_resolve_fields() {
+        let field_names: Rc<[String]> = field_names().into();
+        let line = |class: &str, thread: Option<&str>| DefaultLogLineParseResult::from_slots(
+            field_names.clone(),
+            vec![Some(String::from(class)), thread.map(String::from)],
+            None,
+        );
+
+        let mut program = Program::new();
+        program.push(equals("class", "A"));
+        program.resolve_fields(field_names.clone());
+        assert!(matches!(&program.instructions[0], Instruction::Field(FieldRef::Slot(name, 0), _) if name == "class"));
+        assert_eq!(program.check(&line("A", None)), true);
+        assert_eq!(program.check(&line("B", None)), false);
+        // lines with other field names are looked up by name rather than by slot
+        let other_line = DefaultLogLineParseResult::from_slots(
+            vec![String::from("thread"), String::from("class")].into(),
+            vec![Some(String::from("A")), Some(String::from("B"))],
+            None,
+        );
+        assert_eq!(program.check(&other_line), false);
+        assert_eq!(program.check(&log_line("A")), true, "Equal field names that are not shared are looked up by name");
+
+        let mut program = Program::new();
+        program.push(equals("user", "A"));
+        program.resolve_fields(field_names.clone());
+        assert!(matches!(program.instructions[0], Instruction::Field(FieldRef::Absent(_), _)));
+        assert_eq!(program.check(&line("A", None)), false);
+        let user_line = DefaultLogLineParseResult::from_slots(vec![String::from("user")].into(), vec![Some(String::from("A"))], None);
+        assert_eq!(program.check(&user_line), true);
+
+        let mut program = Program::new();
+        program.push(Instruction::Compare(
+            FieldRef::Name(String::from("thread")),
+            FieldRef::Name(String::from("class")),
+            FieldComparisonConstraint::new("thread", FieldRelation::Equal, "class"),
+        ));
+        program.resolve_fields(field_names.clone());
+        assert!(matches!(program.instructions[0], Instruction::Compare(FieldRef::Slot(_, 1), FieldRef::Slot(_, 0), _)));
+        assert_eq!(program.check(&line("A", Some("A"))), true);
+        assert_eq!(program.check(&line("A", None)), false, "Comparisons with a missing field fail");
+    }
+}
@@ -1,5 +1,12 @@
-use chomp::prelude::{parse_only, U8Input, SimpleResult, ParseResult, token, take_till, scan, string, skip_while};
-use simple_error::{bail, SimpleError};
+use chomp::prelude::{parse_only, U8Input, Buffer, SimpleResult, ParseResult, token, take_while, take_while1, string, skip_while, many, option, peek, eof, satisfy, any};
+use chomp::combinators::{look_ahead, matched_by};
+use crate::query::parse_error::{Expected, QueryParseError};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::from_utf8;
+
+/// Result of a query grammar parser, failing with a description of what was expected
+type QueryResult<I, T> = ParseResult<I, T, Expected>;
 
 /// token_to_enum(input, match, return) Returns `return` if tokens on `input` equal `match`.
 fn token_to_enum<I: U8Input, R>(i: I, b: &[u8], r: R) -> ParseResult<I, R,  chomp::parsers::Error<u8>> {
@@ -11,120 +18,972 @@ fn skip_whitespace<I: U8Input>(i: I) -> ParseResult<I, (),  chomp::parsers::Erro
     skip_while(i, |c| (c as char).is_whitespace())
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+/// Check whether the input continues with `prefix`, without consuming anything.
+///
+/// Used to commit to an alternative up front, so that failures inside of it are reported
+/// where they happen instead of being discarded by backtracking.
+fn followed_by<I: U8Input>(i: I, prefix: &'static [u8]) -> QueryResult<I, bool> {
+    option(i, |i| look_ahead(i, |i| string(i, prefix)).map(|_| true), false).map_err(Expected::from)
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 /// Constraint on relationships between a key and a value parsed from a log line
 pub struct QueryAtom<B> {
     pub query_key: B,
     pub query_constraint: QueryConstraint,
-    pub query_value: B,
+    pub query_value: QueryValue<B>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Right hand side of a constraint
+pub enum QueryValue<B> {
+    Literal(B),
+    List(Vec<B>),
+    /// Value of another field of the same log line, written as `field(key)`
+    Field(B),
+    /// Placeholder written as `$name`, which is replaced by the value bound to `name`
+    Parameter(B),
+    /// Used by constraints that only look at the key, e.g. existence checks
+    Empty,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 /// Function describing relationship between key and value in constraint
 pub enum QueryConstraint {
     EQ,
+    IEQ,
+    GLOB,
+    NE,
+    LT,
+    LE,
+    GT,
+    GE,
+    MATCH,
+    NOTMATCH,
+    CONTAINS,
+    STARTSWITH,
+    ENDSWITH,
+    ICONTAINS,
+    ISTARTSWITH,
+    IENDSWITH,
+    IN,
+    NOTIN,
+    EXISTS,
+    MISSING,
+}
+
+impl QueryConstraint {
+    /// Whether this constraint relates a key to a list of values rather than a single value
+    pub fn takes_list(&self) -> bool {
+        matches!(self, QueryConstraint::IN | QueryConstraint::NOTIN)
+    }
+
+    /// The operator as written in a query, or the keyword of an existence check
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            QueryConstraint::EQ => "=",
+            QueryConstraint::IEQ => "=i",
+            QueryConstraint::GLOB => "=*",
+            QueryConstraint::NE => "!=",
+            QueryConstraint::LT => "<",
+            QueryConstraint::LE => "<=",
+            QueryConstraint::GT => ">",
+            QueryConstraint::GE => ">=",
+            QueryConstraint::MATCH => "~",
+            QueryConstraint::NOTMATCH => "!~",
+            QueryConstraint::CONTAINS => "contains",
+            QueryConstraint::STARTSWITH => "startswith",
+            QueryConstraint::ENDSWITH => "endswith",
+            QueryConstraint::ICONTAINS => "icontains",
+            QueryConstraint::ISTARTSWITH => "istartswith",
+            QueryConstraint::IENDSWITH => "iendswith",
+            QueryConstraint::IN => "in",
+            QueryConstraint::NOTIN => "not in",
+            QueryConstraint::EXISTS => "exists",
+            QueryConstraint::MISSING => "missing",
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum QueryOpTerm {
     AND,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum QueryOpExpression{
     OR,
 }
 
 /// Higher precedence parse structure
-#[derive(Debug, Eq, PartialEq, Clone)]
+///
+/// The left hand side of a `Binary` term is always one of the factor variants
+/// (`Unary`, `Not`, `Group`, `FullText`, `Call` or `Reference`).
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum QueryTerm<T> {
     Unary(QueryAtom<T>),
-    Binary(QueryAtom<T>, QueryOpTerm, Box<QueryTerm<T>>),
+    Binary(Box<QueryTerm<T>>, QueryOpTerm, Box<QueryTerm<T>>),
+    Not(Box<QueryTerm<T>>),
+    Group(Box<QueryExpression<T>>),
+    /// Free text that has to appear in any of the captured fields
+    FullText(T),
+    /// Call of a function registered with the query, like `is_internal_ip(client)`, with its arguments as written
+    Call(T, Vec<T>),
+    /// Saved query referred to by name, like `@errors_in_dispatcher`, that is expanded before the query is run
+    Reference(T),
 }
 
 /// Lower precedence parse structure
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum QueryExpression<T> {
     Unary(QueryTerm<T>),
     Binary(QueryTerm<T>, QueryOpExpression, Box<QueryExpression<T>>),
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-/// Parse tree for a set of constraints
+impl<T> QueryValue<T> {
+    /// Convert the values of this right hand side, e.g. to take ownership of them
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> QueryValue<U> {
+        match self {
+            QueryValue::Literal(value) => QueryValue::Literal(f(value)),
+            QueryValue::List(values) => QueryValue::List(values.into_iter().map(f).collect()),
+            QueryValue::Field(key) => QueryValue::Field(f(key)),
+            QueryValue::Parameter(name) => QueryValue::Parameter(f(name)),
+            QueryValue::Empty => QueryValue::Empty,
+        }
+    }
+}
+
+impl<T> QueryAtom<T> {
+    /// Convert the key and values of this atom, e.g. to take ownership of them
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> QueryAtom<U> {
+        QueryAtom { query_key: f(self.query_key), query_constraint: self.query_constraint, query_value: self.query_value.map(f) }
+    }
+
+    /// Replace a placeholder with the value `value` looks up for its name, failing with the name if there is none
+    pub fn bind_parameters<F: Fn(&T) -> Option<T>>(self, value: &F) -> Result<Self, T> {
+        let query_value = match self.query_value {
+            QueryValue::Parameter(name) => match value(&name) {
+                Some(bound) => QueryValue::Literal(bound),
+                None => return Err(name),
+            },
+            query_value => query_value,
+        };
+        Ok(QueryAtom { query_key: self.query_key, query_constraint: self.query_constraint, query_value })
+    }
+}
+
+impl<T> QueryTerm<T> {
+    /// Convert the keys and values of every atom in this term, see `QueryAtom::map`
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> QueryTerm<U> {
+        match self {
+            QueryTerm::Unary(atom) => QueryTerm::Unary(atom.map(f)),
+            QueryTerm::Binary(factor, op, term) => QueryTerm::Binary(Box::new(factor.map(f)), op, Box::new(term.map(f))),
+            QueryTerm::Not(factor) => QueryTerm::Not(Box::new(factor.map(f))),
+            QueryTerm::Group(expression) => QueryTerm::Group(Box::new(expression.map(f))),
+            QueryTerm::FullText(text) => QueryTerm::FullText(f(text)),
+            QueryTerm::Call(name, arguments) => QueryTerm::Call(f(name), arguments.into_iter().map(f).collect()),
+            QueryTerm::Reference(name) => QueryTerm::Reference(f(name)),
+        }
+    }
+
+    /// Replace the placeholders of every atom in this term, see `QueryAtom::bind_parameters`
+    pub fn bind_parameters<F: Fn(&T) -> Option<T>>(self, value: &F) -> Result<Self, T> {
+        Ok(match self {
+            QueryTerm::Unary(atom) => QueryTerm::Unary(atom.bind_parameters(value)?),
+            QueryTerm::Binary(factor, op, term) => QueryTerm::Binary(
+                Box::new(factor.bind_parameters(value)?),
+                op,
+                Box::new(term.bind_parameters(value)?),
+            ),
+            QueryTerm::Not(factor) => QueryTerm::Not(Box::new(factor.bind_parameters(value)?)),
+            QueryTerm::Group(expression) => QueryTerm::Group(Box::new(expression.bind_parameters(value)?)),
+            term => term,
+        })
+    }
+}
+
+impl<T> QueryExpression<T> {
+    /// Convert the keys and values of every atom in this expression, see `QueryAtom::map`
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> QueryExpression<U> {
+        match self {
+            QueryExpression::Unary(term) => QueryExpression::Unary(term.map(f)),
+            QueryExpression::Binary(term, op, expression) => QueryExpression::Binary(term.map(f), op, Box::new(expression.map(f))),
+        }
+    }
+
+    /// Replace the placeholders of every atom in this expression, see `QueryAtom::bind_parameters`
+    pub fn bind_parameters<F: Fn(&T) -> Option<T>>(self, value: &F) -> Result<Self, T> {
+        Ok(match self {
+            QueryExpression::Unary(term) => QueryExpression::Unary(term.bind_parameters(value)?),
+            QueryExpression::Binary(term, op, expression) => QueryExpression::Binary(
+                term.bind_parameters(value)?,
+                op,
+                Box::new(expression.bind_parameters(value)?),
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Field to sort records by
+pub struct SortKey<T> {
+    pub key: T,
+    pub descending: bool,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Function that summarizes a field over a group of records
+pub enum AggregateFunction {
+    Count,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggregateFunction {
+    /// Name of the function as written in a query
+    pub fn name(self) -> &'static str {
+        match self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Avg => "avg",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// A function applied to a field of every record in a group, like `avg(latency)`
+pub struct Aggregate<T> {
+    pub function: AggregateFunction,
+    /// Field to aggregate, which only `count` can leave out to count every record
+    pub field: Option<T>,
+    /// Name of the result, instead of the default like `avg(latency)`
+    pub alias: Option<T>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Unit of a time span
+pub enum SpanUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl SpanUnit {
+    /// Suffix of the unit as written in a query
+    pub fn suffix(self) -> &'static str {
+        match self {
+            SpanUnit::Second => "s",
+            SpanUnit::Minute => "m",
+            SpanUnit::Hour => "h",
+            SpanUnit::Day => "d",
+        }
+    }
+
+    pub fn seconds(self) -> i64 {
+        match self {
+            SpanUnit::Second => 1,
+            SpanUnit::Minute => 60,
+            SpanUnit::Hour => 60 * 60,
+            SpanUnit::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Length of a time window, like `5m`
+pub struct Span {
+    pub amount: usize,
+    pub unit: SpanUnit,
+}
+
+impl Span {
+    /// Length of the span in milliseconds, or `None` if it does not fit an `i64`
+    pub fn milliseconds(self) -> Option<i64> {
+        i64::try_from(self.amount).ok()?.checked_mul(self.unit.seconds())?.checked_mul(1000)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Function that can be called in the expression of an `eval` stage
+pub enum EvalFunction {
+    Int,
+    Num,
+    Round,
+    Abs,
+    Len,
+    Lower,
+    Upper,
+    Trim,
+    Substr,
+    Concat,
+    If,
+    Coalesce,
+}
+
+/// Every function that can be called in an `eval` expression
+const EVAL_FUNCTIONS: [EvalFunction; 12] = [
+    EvalFunction::Int,
+    EvalFunction::Num,
+    EvalFunction::Round,
+    EvalFunction::Abs,
+    EvalFunction::Len,
+    EvalFunction::Lower,
+    EvalFunction::Upper,
+    EvalFunction::Trim,
+    EvalFunction::Substr,
+    EvalFunction::Concat,
+    EvalFunction::If,
+    EvalFunction::Coalesce,
+];
+
+impl EvalFunction {
+    /// Name of the function as written in a query
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalFunction::Int => "int",
+            EvalFunction::Num => "num",
+            EvalFunction::Round => "round",
+            EvalFunction::Abs => "abs",
+            EvalFunction::Len => "len",
+            EvalFunction::Lower => "lower",
+            EvalFunction::Upper => "upper",
+            EvalFunction::Trim => "trim",
+            EvalFunction::Substr => "substr",
+            EvalFunction::Concat => "concat",
+            EvalFunction::If => "if",
+            EvalFunction::Coalesce => "coalesce",
+        }
+    }
+
+    /// Least and most number of arguments the function takes, where `None` means any number
+    pub fn arity(self) -> (usize, Option<usize>) {
+        match self {
+            EvalFunction::Int | EvalFunction::Num | EvalFunction::Abs | EvalFunction::Len
+                | EvalFunction::Lower | EvalFunction::Upper | EvalFunction::Trim => (1, Some(1)),
+            EvalFunction::Round => (1, Some(2)),
+            EvalFunction::Substr => (2, Some(3)),
+            EvalFunction::If => (3, Some(3)),
+            EvalFunction::Concat | EvalFunction::Coalesce => (1, None),
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        EVAL_FUNCTIONS.iter().copied().find(|function| function.name().as_bytes() == name)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// Binary operator of an `eval` expression
+pub enum EvalOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// Operators of each precedence level, where operators that are prefixes of others come last
+const COMPARISON_OPERATORS: [EvalOperator; 6] = [
+    EvalOperator::Equal,
+    EvalOperator::NotEqual,
+    EvalOperator::LessEqual,
+    EvalOperator::GreaterEqual,
+    EvalOperator::Less,
+    EvalOperator::Greater,
+];
+const SUM_OPERATORS: [EvalOperator; 2] = [EvalOperator::Add, EvalOperator::Subtract];
+const PRODUCT_OPERATORS: [EvalOperator; 3] = [EvalOperator::Multiply, EvalOperator::Divide, EvalOperator::Remainder];
+
+impl EvalOperator {
+    /// Symbol of the operator as written in a query
+    pub fn symbol(self) -> &'static str {
+        match self {
+            EvalOperator::Add => "+",
+            EvalOperator::Subtract => "-",
+            EvalOperator::Multiply => "*",
+            EvalOperator::Divide => "/",
+            EvalOperator::Remainder => "%",
+            EvalOperator::Equal => "==",
+            EvalOperator::NotEqual => "!=",
+            EvalOperator::Less => "<",
+            EvalOperator::LessEqual => "<=",
+            EvalOperator::Greater => ">",
+            EvalOperator::GreaterEqual => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Expression computing the value of a field in an `eval` stage
+pub enum EvalExpression<T> {
+    /// Value of a field of the record
+    Field(T),
+    /// Quoted string
+    Text(T),
+    Number(T),
+    Call(EvalFunction, Vec<EvalExpression<T>>),
+    Binary(Box<EvalExpression<T>>, EvalOperator, Box<EvalExpression<T>>),
+}
+
+impl<T> EvalExpression<T> {
+    /// Convert the fields and literals of this expression, e.g. to take ownership of them
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> EvalExpression<U> {
+        match self {
+            EvalExpression::Field(field) => EvalExpression::Field(f(field)),
+            EvalExpression::Text(text) => EvalExpression::Text(f(text)),
+            EvalExpression::Number(number) => EvalExpression::Number(f(number)),
+            EvalExpression::Call(function, arguments) => EvalExpression::Call(
+                function,
+                arguments.into_iter().map(|argument| argument.map(f)).collect(),
+            ),
+            EvalExpression::Binary(left, operator, right) => EvalExpression::Binary(Box::new(left.map(f)), operator, Box::new(right.map(f))),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Field set to the value of an expression, like `ms = int(latency) * 1000`
+pub struct EvalAssignment<T> {
+    pub field: T,
+    pub expression: EvalExpression<T>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Processing step that log lines passing the filter go through, in order
+pub enum QueryStage<T> {
+    /// Only keep the given fields
+    Fields(Vec<T>),
+    /// Order records by the given keys, the first one taking precedence
+    Sort(Vec<SortKey<T>>),
+    /// Only keep the first n records
+    Head(usize),
+    /// Only keep the last n records
+    Tail(usize),
+    /// Replace the records with one row of aggregates per distinct value of the `by` fields
+    Stats {
+        aggregates: Vec<Aggregate<T>>,
+        by: Vec<T>,
+    },
+    /// Replace the records with one row of aggregates per time window, with a column per
+    /// aggregate and distinct value of the `by` field
+    Timechart {
+        span: Span,
+        aggregates: Vec<Aggregate<T>>,
+        by: Option<T>,
+    },
+    /// Set fields to the values of expressions, in order
+    Eval(Vec<EvalAssignment<T>>),
+}
+
+impl<T> QueryStage<T> {
+    /// Convert the keys of this stage, e.g. to take ownership of them
+    pub fn map<U, F: Fn(T) -> U>(self, f: F) -> QueryStage<U> {
+        match self {
+            QueryStage::Fields(keys) => QueryStage::Fields(keys.into_iter().map(&f).collect()),
+            QueryStage::Sort(keys) => QueryStage::Sort(keys.into_iter().map(|sort_key| SortKey {
+                key: f(sort_key.key),
+                descending: sort_key.descending,
+            }).collect()),
+            QueryStage::Head(count) => QueryStage::Head(count),
+            QueryStage::Tail(count) => QueryStage::Tail(count),
+            QueryStage::Stats { aggregates, by } => QueryStage::Stats {
+                aggregates: aggregates.into_iter().map(|aggregate| Aggregate {
+                    function: aggregate.function,
+                    field: aggregate.field.map(&f),
+                    alias: aggregate.alias.map(&f),
+                }).collect(),
+                by: by.into_iter().map(&f).collect(),
+            },
+            QueryStage::Timechart { span, aggregates, by } => QueryStage::Timechart {
+                span,
+                aggregates: aggregates.into_iter().map(|aggregate| Aggregate {
+                    function: aggregate.function,
+                    field: aggregate.field.map(&f),
+                    alias: aggregate.alias.map(&f),
+                }).collect(),
+                by: by.map(&f),
+            },
+            QueryStage::Eval(assignments) => QueryStage::Eval(assignments.into_iter().map(|assignment| EvalAssignment {
+                field: f(assignment.field),
+                expression: assignment.expression.map(&f),
+            }).collect()),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Parse tree for a set of constraints, followed by the stages of the pipeline
+///
+/// Trees are exchanged as JSON in their `Query<String>` form, with escape sequences replaced
+/// through `map(&unescape)`. Other buffers, like the `&[u8]` of `parse_query`, serialize as
+/// arrays of numbers.
 pub struct Query<T>{
-    pub tree: QueryExpression<T>
+    pub tree: QueryExpression<T>,
+    #[serde(default)]
+    pub stages: Vec<QueryStage<T>>,
 }
 
-/// Parse a single query atom which is a constraint to use in query processing
-fn query_atom<I: U8Input>(i: I) -> SimpleResult<I, QueryAtom<I::Buffer>> {
+impl<T> Query<T> {
+    /// Convert the keys and values of the filter and the stages, e.g. to take ownership of them
+    pub fn map<U, F: Fn(T) -> U>(self, f: &F) -> Query<U> {
+        Query {
+            tree: self.tree.map(f),
+            stages: self.stages.into_iter().map(|stage| stage.map(f)).collect(),
+        }
+    }
+}
+
+/// Parse what follows the key of an atom, either an existence check or a constraint and its value
+fn query_atom_rest<I: U8Input>(i: I, key: I::Buffer, next: Option<u8>) -> QueryResult<I, QueryAtom<I::Buffer>> {
+    if next == Some(b'(') && is_existence_keyword(&key) {
+        let query_constraint = if key.to_vec() == b"exists" { QueryConstraint::EXISTS } else { QueryConstraint::MISSING };
+        return parse!{i;
+            let _ = token(b'(');
+            skip_whitespace();
+            let query_key = query_key();
+            skip_whitespace();
+            let _ = token(b')');
+            ret QueryAtom {
+                query_key,
+                query_constraint,
+                query_value: QueryValue::Empty,
+            }
+        }
+    }
     parse!{i;
-        // take the first token up until =
-        let query_key = take_till(|c| c == b'=');
         let query_constraint = query_constraint();
-        let _ = token(b'"');
-        let query_value = scan(false, |s, c| if s { Some(false) }
-                                             else if c == b'"' { None }
-                                             else { Some(c == b'\\') });
-        let _ = token(b'"');
+        skip_whitespace();
+        let query_value = query_value(query_constraint.takes_list());
         ret QueryAtom {
-            query_key,
+            query_key: key,
             query_constraint,
             query_value,
         }
     }
 }
 
-fn query_expression<I: U8Input>(i: I) -> SimpleResult<I, QueryExpression<I::Buffer>> {
-    fn binary_parser<I: U8Input>(i: I) -> SimpleResult<I, QueryExpression<I::Buffer>> {
-        parse!{i;
-            let term = query_term();
+/// Whether a key is one of the keywords that start an existence check, like `exists(key)`
+fn is_existence_keyword<B: Buffer<Token=u8>>(key: &B) -> bool {
+    let key = key.to_vec();
+    key == b"exists" || key == b"missing"
+}
+
+/// Parse the key of an atom
+fn query_key<I: U8Input>(i: I) -> QueryResult<I, I::Buffer> {
+    take_while1(i, is_key_char).map_err(|_| Expected::Element("key"))
+}
+
+/// Whether a character starts and ends a quoted string
+fn is_quote(c: u8) -> bool {
+    c == b'"' || c == b'\''
+}
+
+/// Check whether the input continues with a quoted string, without consuming anything
+fn followed_by_quote<I: U8Input>(i: I) -> QueryResult<I, bool> {
+    peek(i).map(|next| next.is_some_and(is_quote)).map_err(Expected::from)
+}
+
+/// Parse the hexadecimal digits of a code point written as `u{1F600}`
+fn code_point_digits<I: U8Input>(i: I) -> SimpleResult<I, I::Buffer> {
+    parse!{i;
+        string(b"u{");
+        let digits = take_while1(|c| c.is_ascii_hexdigit());
+        token(b'}');
+        ret digits
+    }
+}
+
+/// Parse a code point written as `u{1F600}`, failing before it if it is not a valid character
+fn code_point<I: U8Input>(i: I) -> QueryResult<I, ()> {
+    option(i, |i| look_ahead(i, code_point_digits).map(Some), None).map_err(Expected::from).bind(|i, digits| {
+        let valid = digits.is_some_and(|digits| {
+            let digits = digits.to_vec();
+            digits.len() <= 6 && from_utf8(&digits).ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .and_then(char::from_u32)
+                .is_some()
+        });
+        if valid {
+            code_point_digits(i).map(|_| ()).map_err(Expected::from)
+        } else {
+            i.err(Expected::Element("escape sequence"))
+        }
+    })
+}
+
+/// Parse an escape sequence, one of `\"`, `\'`, `\\`, `\n`, `\t` or a code point like `\u{1F600}`
+fn escape_sequence<I: U8Input>(i: I) -> QueryResult<I, ()> {
+    fn escape_rest<I: U8Input>(i: I, next: Option<u8>) -> QueryResult<I, ()> {
+        match next {
+            Some(b'"') | Some(b'\'') | Some(b'\\') | Some(b'n') | Some(b't') => any(i).map(|_| ()).map_err(Expected::from),
+            Some(b'u') => code_point(i),
+            _ => i.err(Expected::Element("escape sequence")),
+        }
+    }
+
+    parse!{i;
+        let _ = token(b'\\');
+        let next = peek();
+        escape_rest(next)
+    }
+}
+
+/// Parse the contents of a quoted string up to its closing quote
+fn quoted_contents<I: U8Input>(i: I, quote: u8) -> QueryResult<I, ()> {
+    peek(i).map_err(Expected::from).bind(|i, next| match next {
+        Some(c) if c == quote => i.ret(()),
+        Some(b'\\') => escape_sequence(i).bind(|i, _| quoted_contents(i, quote)),
+        Some(_) => take_while1(i, |c| c != quote && c != b'\\').map_err(Expected::from).bind(|i, _| quoted_contents(i, quote)),
+        None => i.err(Expected::Token(quote)),
+    })
+}
+
+/// Parse a single or double quoted string, keeping escape sequences as they are written
+///
+/// The escape sequences are checked here, and are replaced by the characters they stand for
+/// with `unescape`.
+fn query_string<I: U8Input>(i: I) -> QueryResult<I, I::Buffer> {
+    parse!{i;
+        let quote = satisfy(is_quote);
+        let contents = matched_by(|i| quoted_contents(i, quote));
+        let _ = token(quote);
+        ret contents.0
+    }
+}
+
+/// Replace the escape sequences of the contents of a quoted string by the characters they stand for
+///
+/// Text without a backslash, like any key or unquoted value, stays the same.
+pub fn unescape<T: AsRef<[u8]>>(raw: T) -> String {
+    let raw = String::from_utf8_lossy(raw.as_ref()).into_owned();
+    if !raw.contains('\\') {
+        return raw
+    }
+    let mut text = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                text.extend(u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32));
+            },
+            Some(c) => text.push(c),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+/// Whether a character can be part of an unquoted value
+fn is_bare_value_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'.'
+}
+
+/// Parse a single value, either quoted or a bare word like `WARN` or `500`
+fn query_literal<I: U8Input>(i: I) -> QueryResult<I, I::Buffer> {
+    parse!{i;
+        let quoted = followed_by_quote();
+        query_literal_rest(quoted)
+    }
+}
+
+fn query_literal_rest<I: U8Input>(i: I, quoted: bool) -> QueryResult<I, I::Buffer> {
+    if quoted {
+        query_string(i)
+    } else {
+        take_while1(i, is_bare_value_char).map_err(|_| Expected::Element("value"))
+    }
+}
+
+/// Parse one or more items separated by commas
+fn separated_list<I: U8Input, T, F>(i: I, item: F) -> QueryResult<I, Vec<T>>
+    where F: Fn(I) -> QueryResult<I, T> + Copy {
+    parse!{i;
+        let first = item();
+        let rest: Vec<T> = many(|i| parse!{i;
             skip_whitespace();
-            let op = query_op_expression();
+            let _ = token(b',');
             skip_whitespace();
-            let expr = query_expression();
-            ret QueryExpression::Binary(term, op, Box::new(expr))
+            item()
+        });
+        ret {
+            let mut items = vec![first];
+            items.extend(rest);
+            items
         }
     }
-    fn unary_parser<I: U8Input>(i: I) -> SimpleResult<I, QueryExpression<I::Buffer>> {
+}
+
+/// Parse the arguments of a function call, each either a key or a quoted value
+fn call_arguments<I: U8Input>(i: I, empty: bool) -> QueryResult<I, Vec<I::Buffer>> {
+    fn call_argument<I: U8Input>(i: I, quoted: bool) -> QueryResult<I, I::Buffer> {
+        if quoted {
+            query_string(i)
+        } else {
+            take_while1(i, is_key_char).map_err(|_| Expected::Element("argument"))
+        }
+    }
+
+    if empty {
+        return i.ret(Vec::new())
+    }
+    separated_list(i, |i| followed_by_quote(i).bind(call_argument))
+}
+
+fn call_end<I: U8Input>(i: I) -> QueryResult<I, u8> {
+    token(i, b')').map_err(|_| Expected::Element("',' or ')'"))
+}
+
+/// Parse the right hand side of an atom, either a single value or a parenthesized list of values
+fn query_value<I: U8Input>(i: I, list: bool) -> QueryResult<I, QueryValue<I::Buffer>> {
+    fn list_contents<I: U8Input>(i: I, empty: bool) -> QueryResult<I, Vec<I::Buffer>> {
+        if empty {
+            i.ret(Vec::new())
+        } else {
+            separated_list(i, query_literal)
+        }
+    }
+
+    fn list_end<I: U8Input>(i: I) -> QueryResult<I, u8> {
+        token(i, b')').map_err(|_| Expected::Element("',' or ')'"))
+    }
+
+    fn parameter<I: U8Input>(i: I, parameter: bool) -> QueryResult<I, QueryValue<I::Buffer>> {
+        if !parameter {
+            return query_literal(i).map(QueryValue::Literal)
+        }
         parse!{i;
-            let term = query_term();
-            ret QueryExpression::Unary(term)
+            let _ = token(b'$');
+            let name = query_key();
+            ret QueryValue::Parameter(name)
         }
     }
+
+    fn field_reference<I: U8Input>(i: I, field: bool) -> QueryResult<I, QueryValue<I::Buffer>> {
+        if !field {
+            return followed_by(i, b"$").bind(parameter)
+        }
+        parse!{i;
+            let _ = string(b"field(");
+            skip_whitespace();
+            let key = query_key();
+            skip_whitespace();
+            let _ = token(b')');
+            ret QueryValue::Field(key)
+        }
+    }
+
+    if !list {
+        return followed_by(i, b"field(").bind(field_reference)
+    }
+    parse!{i;
+        let _ = token(b'(');
+        skip_whitespace();
+        let empty = followed_by(b")");
+        let values = list_contents(empty);
+        skip_whitespace();
+        list_end();
+        ret QueryValue::List(values)
+    }
+}
+
+fn query_expression<I: U8Input>(i: I) -> QueryResult<I, QueryExpression<I::Buffer>> {
+    parse!{i;
+        let term = query_term();
+        skip_whitespace();
+        let binary = followed_by(b"||");
+        query_expression_rest(term, binary)
+    }
+}
+
+fn query_expression_rest<I: U8Input>(i: I, term: QueryTerm<I::Buffer>, binary: bool) -> QueryResult<I, QueryExpression<I::Buffer>> {
+    if !binary {
+        return i.ret(QueryExpression::Unary(term))
+    }
+    parse!{i;
+        let op = query_op_expression();
+        skip_whitespace();
+        let expr = query_expression();
+        ret QueryExpression::Binary(term, op, Box::new(expr))
+    }
+}
+
+fn query_term<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
     parse!{i;
-        binary_parser() <|>
-        unary_parser()
+        let factor = query_factor();
+        skip_whitespace();
+        let binary = followed_by(b"&&");
+        query_term_rest(factor, binary)
     }
 }
 
-fn query_term<I: U8Input>(i: I) -> SimpleResult<I, QueryTerm<I::Buffer>> {
-    fn query_term_binary<I: U8Input>(i: I) -> SimpleResult<I, QueryTerm<I::Buffer>> {
+fn query_term_rest<I: U8Input>(i: I, factor: QueryTerm<I::Buffer>, binary: bool) -> QueryResult<I, QueryTerm<I::Buffer>> {
+    if !binary {
+        return i.ret(factor)
+    }
+    parse!{i;
+        let op = query_op_term();
+        skip_whitespace();
+        let term = query_term();
+        ret QueryTerm::Binary(Box::new(factor), op, Box::new(term))
+    }
+}
+
+/// Whether the input continues with a reference to a saved query like `@errors`, rather than with
+/// an atom on a key like `@time`, without consuming anything
+fn followed_by_reference<I: U8Input>(i: I) -> SimpleResult<I, bool> {
+    fn reference_end<I: U8Input>(i: I) -> SimpleResult<I, bool> {
+        parse!{i;
+            let _ = token(b'@');
+            let _ = take_while1(is_key_char);
+            skip_whitespace();
+            let next = peek();
+            ret matches!(next, None | Some(b'&') | Some(b'|') | Some(b')'))
+        }
+    }
+
+    option(i, |i| look_ahead(i, reference_end), false)
+}
+
+/// Parse the highest precedence structures: atoms, full text searches, negations and parenthesized expressions
+fn query_factor<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
+    fn query_factor_not<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        parse!{i;
+            let _ = token(b'!');
+            skip_whitespace();
+            let factor = query_factor();
+            ret QueryTerm::Not(Box::new(factor))
+        }
+    }
+
+    fn query_factor_group<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        parse!{i;
+            let _ = token(b'(');
+            skip_whitespace();
+            let expr = query_expression();
+            skip_whitespace();
+            let _ = token(b')');
+            ret QueryTerm::Group(Box::new(expr))
+        }
+    }
+
+    fn query_factor_text<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        parse!{i;
+            let text = query_string();
+            ret QueryTerm::FullText(text)
+        }
+    }
+
+    fn query_factor_atom<I: U8Input>(i: I) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        parse!{i;
+            let key = query_key();
+            skip_whitespace();
+            let next = peek();
+            query_factor_atom_rest(key, next)
+        }
+    }
+
+    /// Parse the rest of an atom, or of a function call if the key is followed by arguments
+    fn query_factor_atom_rest<I: U8Input>(i: I, key: I::Buffer, next: Option<u8>) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        if next != Some(b'(') || is_existence_keyword(&key) {
+            return query_atom_rest(i, key, next).map(QueryTerm::Unary)
+        }
         parse!{i;
-            let atom = query_atom();
+            let _ = token(b'(');
             skip_whitespace();
-            let op = query_op_term();
+            let empty = followed_by(b")");
+            let arguments = call_arguments(empty);
             skip_whitespace();
-            let term = query_term();
-            ret QueryTerm::Binary(atom, op, Box::new(term))
+            call_end();
+            ret QueryTerm::Call(key, arguments)
         }
     }
 
-    fn query_term_unary<I: U8Input>(i: I) -> SimpleResult<I, QueryTerm<I::Buffer>> {
+    fn query_factor_reference<I: U8Input>(i: I, reference: bool) -> QueryResult<I, QueryTerm<I::Buffer>> {
+        if !reference {
+            return query_factor_atom(i)
+        }
         parse!{i;
-            let atom = query_atom();
-            ret QueryTerm::Unary(atom)
+            let _ = token(b'@');
+            let name = query_key();
+            ret QueryTerm::Reference(name)
         }
-    }   
+    }
+
+    peek(i).map_err(Expected::from).bind(|i, next| match next {
+        Some(b'!') => query_factor_not(i),
+        Some(b'(') => query_factor_group(i),
+        Some(b'"') | Some(b'\'') => query_factor_text(i),
+        Some(b'@') => followed_by_reference(i).map_err(Expected::from).bind(query_factor_reference),
+        _ => query_factor_atom(i),
+    })
+}
+
+/// Whether a character can be part of the key of an atom
+///
+/// `@` is allowed so that keys can name properties of a log line other than its captures, like `@time`.
+pub fn is_key_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'.' || c == b'@'
+}
+
+fn query_constraint_not_in<I: U8Input>(i: I) -> SimpleResult<I, QueryConstraint> {
     parse!{i;
-        query_term_binary() <|>
-        query_term_unary()   
+        let _ = string(b"not");
+        skip_whitespace();
+        token_to_enum(b"in", QueryConstraint::NOTIN)
     }
-} 
+}
 
-fn query_constraint<I: U8Input>(i: I) -> SimpleResult<I, QueryConstraint> {
+/// `=i` is only an operator when it is not the start of a bare value, e.g. in `level=info`
+fn query_constraint_ieq<I: U8Input>(i: I) -> SimpleResult<I, QueryConstraint> {
     parse!{i;
-       token_to_enum(b"=", QueryConstraint::EQ)
+        let _ = string(b"=i");
+        let _ = look_ahead(|i| satisfy(i, |c| !is_bare_value_char(c)));
+        ret QueryConstraint::IEQ
+    }
+}
+
+fn query_constraint<I: U8Input>(i: I) -> QueryResult<I, QueryConstraint> {
+    fn query_constraint_token<I: U8Input>(i: I) -> SimpleResult<I, QueryConstraint> {
+        // two character operators have to be tried before their one character prefixes
+        parse!{i;
+           token_to_enum(b"!=", QueryConstraint::NE) <|>
+           token_to_enum(b"!~", QueryConstraint::NOTMATCH) <|>
+           token_to_enum(b"<=", QueryConstraint::LE) <|>
+           token_to_enum(b">=", QueryConstraint::GE) <|>
+           token_to_enum(b"=*", QueryConstraint::GLOB) <|>
+           query_constraint_ieq() <|>
+           token_to_enum(b"<", QueryConstraint::LT) <|>
+           token_to_enum(b">", QueryConstraint::GT) <|>
+           token_to_enum(b"~", QueryConstraint::MATCH) <|>
+           token_to_enum(b"=", QueryConstraint::EQ) <|>
+           token_to_enum(b"contains", QueryConstraint::CONTAINS) <|>
+           token_to_enum(b"startswith", QueryConstraint::STARTSWITH) <|>
+           token_to_enum(b"endswith", QueryConstraint::ENDSWITH) <|>
+           token_to_enum(b"icontains", QueryConstraint::ICONTAINS) <|>
+           token_to_enum(b"istartswith", QueryConstraint::ISTARTSWITH) <|>
+           token_to_enum(b"iendswith", QueryConstraint::IENDSWITH) <|>
+           token_to_enum(b"in", QueryConstraint::IN) <|>
+           query_constraint_not_in()
+        }
     }
+
+    query_constraint_token(i).map_err(|_| Expected::Element("operator"))
 }
 
 fn query_op_term<I: U8Input>(i: I) -> SimpleResult<I, QueryOpTerm> {
@@ -139,21 +998,457 @@ fn query_op_expression<I: U8Input>(i: I) -> SimpleResult<I, QueryOpExpression> {
     }
 }
 
-fn query<I: U8Input>(i: I) -> SimpleResult<I, Query<I::Buffer>> {
+/// Succeed only once the whole query has been consumed
+fn end_of_query<I: U8Input>(i: I) -> QueryResult<I, ()> {
+    eof(i).map_err(|_| Expected::Element("'&&', '||', '|' or the end of the query"))
+}
+
+/// Parse a non-negative number of records
+fn query_count<I: U8Input>(i: I) -> QueryResult<I, usize> {
+    take_while1(i, |c| c.is_ascii_digit()).map_err(|_| Expected::Element("count")).bind(|i, digits| {
+        match String::from_utf8(digits.to_vec()).unwrap().parse() {
+            Ok(count) => i.ret(count),
+            Err(_) => i.err(Expected::Element("count")),
+        }
+    })
+}
+
+/// Parse a key to sort by, which is prefixed with `-` to sort in descending order
+fn query_sort_key<I: U8Input>(i: I) -> QueryResult<I, SortKey<I::Buffer>> {
     parse!{i;
+        let descending = option(|i| token(i, b'-').map(|_| true), false);
+        let key = query_key();
+        ret SortKey {
+            key,
+            descending,
+        }
+    }
+}
+
+/// Parse the name of an aggregate function, without consuming it
+fn aggregate_function<I: U8Input>(i: I) -> QueryResult<I, AggregateFunction> {
+    look_ahead(i, |i| take_while(i, |c| c.is_ascii_alphabetic())).map_err(Expected::from).bind(|i, name| match &name.to_vec()[..] {
+        b"count" => i.ret(AggregateFunction::Count),
+        b"min" => i.ret(AggregateFunction::Min),
+        b"max" => i.ret(AggregateFunction::Max),
+        b"avg" => i.ret(AggregateFunction::Avg),
+        _ => i.err(Expected::Element("aggregate function")),
+    })
+}
+
+/// Parse the field of an aggregate function, which is optional for `count`
+fn aggregate_field<I: U8Input>(i: I, function: AggregateFunction) -> QueryResult<I, Option<I::Buffer>> {
+    if function == AggregateFunction::Count {
+        option(i, |i| query_key(i).map(Some), None)
+    } else {
+        query_key(i).map(Some)
+    }
+}
+
+/// Parse the name an aggregate is given with `as`, if any
+fn aggregate_alias<I: U8Input>(i: I, aliased: bool) -> QueryResult<I, Option<I::Buffer>> {
+    if !aliased {
+        return i.ret(None)
+    }
+    parse!{i;
+        let _ = string(b"as");
+        skip_whitespace();
+        let alias = query_key();
+        ret Some(alias)
+    }
+}
+
+/// Parse an aggregate like `count()` or `avg(latency) as mean_latency`
+fn query_aggregate<I: U8Input>(i: I) -> QueryResult<I, Aggregate<I::Buffer>> {
+    parse!{i;
+        let function = aggregate_function();
+        let _ = take_while1(|c| c.is_ascii_alphabetic());
+        let _ = token(b'(');
+        skip_whitespace();
+        let field = aggregate_field(function);
+        skip_whitespace();
+        let _ = token(b')');
+        skip_whitespace();
+        let aliased = followed_by(b"as");
+        let alias = aggregate_alias(aliased);
+        ret Aggregate {
+            function,
+            field,
+            alias,
+        }
+    }
+}
+
+/// Parse the length of the windows of a `timechart` stage, like `span=5m`
+fn query_span<I: U8Input>(i: I) -> QueryResult<I, Span> {
+    string(i, b"span").map_err(|_| Expected::Element("span")).bind(|i, _| parse!{i;
+        skip_whitespace();
+        let _ = token(b'=');
+        skip_whitespace();
+        let amount = query_count();
+        let unit = query_span_unit();
+        query_span_rest(Span {
+            amount,
+            unit,
+        })
+    })
+}
+
+fn query_span_unit<I: U8Input>(i: I) -> QueryResult<I, SpanUnit> {
+    take_while(i, |c| c.is_ascii_alphabetic()).map_err(Expected::from).bind(|i, unit| match &unit.to_vec()[..] {
+        b"s" => i.ret(SpanUnit::Second),
+        b"m" => i.ret(SpanUnit::Minute),
+        b"h" => i.ret(SpanUnit::Hour),
+        b"d" => i.ret(SpanUnit::Day),
+        _ => i.err(Expected::Element("span unit")),
+    })
+}
+
+/// Reject empty windows
+fn query_span_rest<I: U8Input>(i: I, span: Span) -> QueryResult<I, Span> {
+    if span.amount == 0 || span.milliseconds().is_none() {
+        i.err(Expected::Element("span"))
+    } else {
+        i.ret(span)
+    }
+}
+
+/// Parse the field a `timechart` stage splits its columns by, if any
+fn query_split_by<I: U8Input>(i: I, split: bool) -> QueryResult<I, Option<I::Buffer>> {
+    if !split {
+        return i.ret(None)
+    }
+    parse!{i;
+        let _ = string(b"by");
+        skip_whitespace();
+        let key = query_key();
+        ret Some(key)
+    }
+}
+
+/// Parse the fields a `stats` stage groups by, if any
+fn query_group_by<I: U8Input>(i: I, grouped: bool) -> QueryResult<I, Vec<I::Buffer>> {
+    if !grouped {
+        return i.ret(Vec::new())
+    }
+    parse!{i;
+        let _ = string(b"by");
+        skip_whitespace();
+        separated_list(query_key)
+    }
+}
+
+/// Whether a character can be part of the operator of an `eval` expression
+fn is_eval_operator_char(c: u8) -> bool {
+    b"+-*/%=!<>".contains(&c)
+}
+
+/// Parse one of `operators` after optional whitespace, consuming nothing if there is none
+fn eval_operator<I: U8Input>(i: I, operators: &'static [EvalOperator]) -> QueryResult<I, Option<EvalOperator>> {
+    option(i, |i| parse!{i;
+        skip_whitespace();
+        let symbol = look_ahead(|i| take_while(i, is_eval_operator_char));
+        eval_operator_rest(symbol.to_vec(), operators)
+    }, None)
+}
+
+fn eval_operator_rest<I: U8Input>(i: I, symbol: Vec<u8>, operators: &'static [EvalOperator]) -> QueryResult<I, Option<EvalOperator>> {
+    match operators.iter().find(|operator| symbol.starts_with(operator.symbol().as_bytes())) {
+        Some(operator) => string(i, operator.symbol().as_bytes()).map(|_| Some(*operator)).map_err(Expected::from),
+        None => i.err(Expected::Element("operator")),
+    }
+}
+
+/// Parse the rest of a left associative chain of `operators` between operands parsed by `operand`
+fn eval_chain<I: U8Input, F>(i: I, left: EvalExpression<I::Buffer>, operators: &'static [EvalOperator], operand: F) -> QueryResult<I, EvalExpression<I::Buffer>>
+    where F: Fn(I) -> QueryResult<I, EvalExpression<I::Buffer>> + Copy {
+    eval_operator(i, operators).bind(|i, operator| match operator {
+        Some(operator) => parse!{i;
+            skip_whitespace();
+            let right = operand();
+            eval_chain(EvalExpression::Binary(Box::new(left), operator, Box::new(right)), operators, operand)
+        },
+        None => i.ret(left),
+    })
+}
+
+/// Parse an `eval` expression, which is a sum optionally compared with another sum
+fn eval_operand<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    parse!{i;
+        let left = eval_sum();
+        let operator = eval_operator(&COMPARISON_OPERATORS);
+        eval_comparison_rest(left, operator)
+    }
+}
+
+fn eval_comparison_rest<I: U8Input>(i: I, left: EvalExpression<I::Buffer>, operator: Option<EvalOperator>) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    match operator {
+        Some(operator) => parse!{i;
+            skip_whitespace();
+            let right = eval_sum();
+            ret EvalExpression::Binary(Box::new(left), operator, Box::new(right))
+        },
+        None => i.ret(left),
+    }
+}
+
+fn eval_sum<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    parse!{i;
+        let left = eval_product();
+        eval_chain(left, &SUM_OPERATORS, eval_product)
+    }
+}
+
+fn eval_product<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    parse!{i;
+        let left = eval_unit();
+        eval_chain(left, &PRODUCT_OPERATORS, eval_unit)
+    }
+}
+
+/// Whether a bare word of an `eval` expression is a number rather than the name of a field
+fn is_number(word: &[u8]) -> bool {
+    let digits = if word.starts_with(b"-") { &word[1..] } else { word };
+    digits.first().is_some_and(u8::is_ascii_digit)
+        && std::str::from_utf8(word).is_ok_and(|word| word.parse::<f64>().is_ok())
+}
+
+/// Whether the input continues with a word directly followed by `(`, without consuming anything
+fn followed_by_call<I: U8Input>(i: I) -> SimpleResult<I, bool> {
+    look_ahead(i, |i| parse!{i;
+        let _ = take_while(is_key_char);
+        let next = peek();
+        ret next == Some(b'(')
+    })
+}
+
+/// Parse a string, number, field, function call or parenthesized expression
+fn eval_unit<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    fn eval_unit_group<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+        parse!{i;
+            let _ = token(b'(');
+            skip_whitespace();
+            let expression = eval_operand();
+            skip_whitespace();
+            let _ = token(b')');
+            ret expression
+        }
+    }
+
+    fn eval_unit_text<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+        parse!{i;
+            let text = query_string();
+            ret EvalExpression::Text(text)
+        }
+    }
+
+    fn eval_unit_word<I: U8Input>(i: I) -> QueryResult<I, EvalExpression<I::Buffer>> {
+        parse!{i;
+            let word = look_ahead(|i| take_while(i, is_key_char));
+            let call = followed_by_call();
+            eval_unit_word_rest(word.to_vec(), call)
+        }
+    }
+
+    peek(i).map_err(Expected::from).bind(|i, next| match next {
+        Some(b'(') => eval_unit_group(i),
+        Some(b'"') | Some(b'\'') => eval_unit_text(i),
+        _ => eval_unit_word(i),
+    })
+}
+
+fn eval_unit_word_rest<I: U8Input>(i: I, word: Vec<u8>, call: bool) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    if call {
+        return match EvalFunction::from_name(&word) {
+            Some(function) => eval_call(i, function),
+            None => i.err(Expected::Element("function")),
+        }
+    }
+    take_while1(i, is_key_char).map_err(|_| Expected::Element("expression")).map(|word| {
+        if is_number(&word.to_vec()) {
+            EvalExpression::Number(word)
+        } else {
+            EvalExpression::Field(word)
+        }
+    })
+}
+
+/// Parse a call of a function like `substr(class, 0, 10)`
+fn eval_call<I: U8Input>(i: I, function: EvalFunction) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    parse!{i;
+        let _ = take_while1(is_key_char);
+        let _ = token(b'(');
+        skip_whitespace();
+        let first = eval_operand();
+        eval_arguments(function, vec![first])
+    }
+}
+
+/// Parse the remaining arguments of a call, accepting only as many as the function takes
+fn eval_arguments<I: U8Input>(i: I, function: EvalFunction, arguments: Vec<EvalExpression<I::Buffer>>) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    parse!{i;
+        skip_whitespace();
+        let more = followed_by(b",");
+        eval_arguments_rest(function, arguments, more)
+    }
+}
+
+fn eval_arguments_rest<I: U8Input>(i: I, function: EvalFunction, mut arguments: Vec<EvalExpression<I::Buffer>>, more: bool) -> QueryResult<I, EvalExpression<I::Buffer>> {
+    let (least, most) = function.arity();
+    if more && most.map_or(true, |most| arguments.len() < most) {
+        parse!{i;
+            let _ = token(b',');
+            skip_whitespace();
+            let argument = eval_operand();
+            eval_arguments(function, {
+                arguments.push(argument);
+                arguments
+            })
+        }
+    } else if arguments.len() < least {
+        i.err(Expected::Token(b','))
+    } else {
+        parse!{i;
+            let _ = token(b')');
+            ret EvalExpression::Call(function, arguments)
+        }
+    }
+}
+
+/// Parse an assignment of an `eval` stage like `ms = int(latency) * 1000`
+fn eval_assignment<I: U8Input>(i: I) -> QueryResult<I, EvalAssignment<I::Buffer>> {
+    parse!{i;
+        let field = query_key();
+        skip_whitespace();
+        let _ = token(b'=');
+        skip_whitespace();
+        let expression = eval_operand();
+        ret EvalAssignment {
+            field,
+            expression,
+        }
+    }
+}
+
+/// Parse a pipeline stage like `| head 10`
+fn query_stage<I: U8Input>(i: I) -> QueryResult<I, QueryStage<I::Buffer>> {
+    parse!{i;
+        let _ = token(b'|');
+        skip_whitespace();
+        let name = look_ahead(|i| take_while(i, |c| c.is_ascii_alphabetic()));
+        query_stage_rest(name.to_vec())
+    }
+}
+
+fn query_stage_rest<I: U8Input>(i: I, name: Vec<u8>) -> QueryResult<I, QueryStage<I::Buffer>> {
+    match &name[..] {
+        b"fields" => parse!{i;
+            let _ = string(b"fields");
+            skip_whitespace();
+            let keys = separated_list(query_key);
+            ret QueryStage::Fields(keys)
+        },
+        b"sort" => parse!{i;
+            let _ = string(b"sort");
+            skip_whitespace();
+            let keys = separated_list(query_sort_key);
+            ret QueryStage::Sort(keys)
+        },
+        b"head" => parse!{i;
+            let _ = string(b"head");
+            skip_whitespace();
+            let count = query_count();
+            ret QueryStage::Head(count)
+        },
+        b"tail" => parse!{i;
+            let _ = string(b"tail");
+            skip_whitespace();
+            let count = query_count();
+            ret QueryStage::Tail(count)
+        },
+        b"stats" => parse!{i;
+            let _ = string(b"stats");
+            skip_whitespace();
+            let aggregates = separated_list(query_aggregate);
+            skip_whitespace();
+            let grouped = followed_by(b"by");
+            let by = query_group_by(grouped);
+            ret QueryStage::Stats {
+                aggregates,
+                by,
+            }
+        },
+        b"timechart" => parse!{i;
+            let _ = string(b"timechart");
+            skip_whitespace();
+            let span = query_span();
+            skip_whitespace();
+            let aggregates = separated_list(query_aggregate);
+            skip_whitespace();
+            let split = followed_by(b"by");
+            let by = query_split_by(split);
+            ret QueryStage::Timechart {
+                span,
+                aggregates,
+                by,
+            }
+        },
+        b"eval" => parse!{i;
+            let _ = string(b"eval");
+            skip_whitespace();
+            let assignments = separated_list(eval_assignment);
+            ret QueryStage::Eval(assignments)
+        },
+        _ => i.err(Expected::Element("stage")),
+    }
+}
+
+/// Parse the stages following the filter, if any
+fn query_stages<I: U8Input>(i: I) -> QueryResult<I, Vec<QueryStage<I::Buffer>>> {
+    parse!{i;
+        let more = followed_by(b"|");
+        query_stages_rest(more)
+    }
+}
+
+fn query_stages_rest<I: U8Input>(i: I, more: bool) -> QueryResult<I, Vec<QueryStage<I::Buffer>>> {
+    if !more {
+        return i.ret(Vec::new())
+    }
+    parse!{i;
+        let stage = query_stage();
+        skip_whitespace();
+        let rest = query_stages();
+        ret {
+            let mut stages = vec![stage];
+            stages.extend(rest);
+            stages
+        }
+    }
+}
+
+fn query<I: U8Input>(i: I) -> QueryResult<I, Query<I::Buffer>> {
+    parse!{i;
+        skip_whitespace();
         let expr = query_expression();
+        skip_whitespace();
+        let stages = query_stages();
+        end_of_query();
         ret @ Query<I::Buffer>, _: Query{
             tree: expr,
+            stages,
         }
     }
 }
 
 /// Parse a query into a result, if valid
-pub fn parse_query(query_raw: &str) -> Result<Query<&[u8]>, SimpleError> {
+///
+/// On failure the error records the byte offset parsing stopped at and what was expected there.
+pub fn parse_query(query_raw: &str) -> Result<Query<&[u8]>, QueryParseError> {
     let parse_result = parse_only(query, query_raw.as_bytes());
     match parse_result {
         Ok(x) => Ok(x),
-        Err(x) => bail!("Unable to parse query {}", x.1)
+        Err((remaining, expected)) => Err(QueryParseError::new(query_raw, query_raw.len() - remaining.len(), expected)),
     }
 }
 
@@ -164,13 +1459,97 @@ mod tests {
 
     #[test]
     fn test_parse_atom() -> Result<(), CError<u8>> {
-        let parse_result = parse_only(|i| query_atom(i), b"key=\"value\"").unwrap();
+        let parse_result = parse_only(|i| query_factor(i), b"key=\"value\"").unwrap();
         assert!(
-            parse_result == QueryAtom{query_key: "key".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: "value".as_bytes()}
+            parse_result == QueryTerm::Unary(QueryAtom{query_key: "key".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("value".as_bytes())})
         );
         Ok(())
     }
 
+    #[test]
+    fn test_parse_comparison_atoms() {
+        let cases = [
+            ("status!=\"200\"", "status", QueryConstraint::NE, "200"),
+            ("latency<\"500\"", "latency", QueryConstraint::LT, "500"),
+            ("latency<=\"500\"", "latency", QueryConstraint::LE, "500"),
+            ("latency>\"500\"", "latency", QueryConstraint::GT, "500"),
+            ("latency>=\"500\"", "latency", QueryConstraint::GE, "500"),
+            ("content~\"Snapshotting.*enabled\"", "content", QueryConstraint::MATCH, "Snapshotting.*enabled"),
+            ("content!~\"^Process\"", "content", QueryConstraint::NOTMATCH, "^Process"),
+            ("content contains \"timeout\"", "content", QueryConstraint::CONTAINS, "timeout"),
+            ("thread startswith \"ImageManager\"", "thread", QueryConstraint::STARTSWITH, "ImageManager"),
+            ("class endswith \"Impl\"", "class", QueryConstraint::ENDSWITH, "Impl"),
+            ("content icontains \"TimeOut\"", "content", QueryConstraint::ICONTAINS, "TimeOut"),
+            ("thread istartswith \"imagemanager\"", "thread", QueryConstraint::ISTARTSWITH, "imagemanager"),
+            ("class iendswith \"IMPL\"", "class", QueryConstraint::IENDSWITH, "IMPL"),
+            ("@time >= \"2020/07/17 23:00:00\"", "@time", QueryConstraint::GE, "2020/07/17 23:00:00"),
+            ("level >= WARN", "level", QueryConstraint::GE, "WARN"),
+            ("latency<500", "latency", QueryConstraint::LT, "500"),
+            ("thread=*\"dispatcher-*\"", "thread", QueryConstraint::GLOB, "dispatcher-*"),
+            ("class =i \"imagemanagerimpl\"", "class", QueryConstraint::IEQ, "imagemanagerimpl"),
+            ("class=i\"imagemanagerimpl\"", "class", QueryConstraint::IEQ, "imagemanagerimpl"),
+            ("level=info", "level", QueryConstraint::EQ, "info"),
+            ("level=i", "level", QueryConstraint::EQ, "i"),
+        ];
+        for (raw, key, query_constraint, value) in cases.iter() {
+            let parse_result = parse_only(|i| query_factor(i), raw.as_bytes()).unwrap();
+            assert_eq!(
+                parse_result,
+                QueryTerm::Unary(QueryAtom{query_key: key.as_bytes(), query_constraint: query_constraint.clone(), query_value: QueryValue::Literal(value.as_bytes())}),
+                "Parsing {}", raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_list_atoms() {
+        let parse_result = parse_only(|i| query_factor(i), b"class in (\"A\", \"B\",\"C\")").unwrap();
+        assert_eq!(
+            parse_result,
+            QueryTerm::Unary(QueryAtom{
+                query_key: "class".as_bytes(),
+                query_constraint: QueryConstraint::IN,
+                query_value: QueryValue::List(vec!["A".as_bytes(), "B".as_bytes(), "C".as_bytes()]),
+            })
+        );
+        let parse_result = parse_only(|i| query_factor(i), b"class not  in(\"A\", B)").unwrap();
+        assert_eq!(
+            parse_result,
+            QueryTerm::Unary(QueryAtom{query_key: "class".as_bytes(), query_constraint: QueryConstraint::NOTIN, query_value: QueryValue::List(vec!["A".as_bytes(), "B".as_bytes()])})
+        );
+        assert!(parse_only(|i| query_factor(i), b"class in \"A\"").is_err(), "Set membership needs a list");
+        assert!(parse_only(|i| query_factor(i), b"class=(\"A\")").is_err(), "Equality needs a single value");
+    }
+
+    #[test]
+    fn test_parse_existence() {
+        let parsed = parse_only(|i| query(i), b"exists( client_id ) && missing(user)").unwrap();
+        let existence = |query_key: &'static str, query_constraint: QueryConstraint| QueryTerm::Unary(
+            QueryAtom {query_key: query_key.as_bytes(), query_constraint, query_value: QueryValue::Empty}
+        );
+        assert_eq!(
+            parsed,
+            Query {
+                tree: QueryExpression::Unary(
+                    QueryTerm::Binary(
+                        Box::new(existence("client_id", QueryConstraint::EXISTS)),
+                        QueryOpTerm::AND,
+                        Box::new(existence("user", QueryConstraint::MISSING)),
+                    )
+                ),
+                stages: vec![],
+            }
+        );
+        let parsed = parse_only(|i| query(i), b"exists=\"yes\"").unwrap();
+        assert_eq!(
+            parsed.tree,
+            QueryExpression::Unary(QueryTerm::Unary(
+                QueryAtom {query_key: "exists".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("yes".as_bytes())}
+            )),
+            "Keywords can still be used as keys"
+        );
+    }
+
     #[test]
     fn test_parse_operator() -> Result<(), CError<u8>> {
         let and = parse_only(|i| query_op_term(i), b"&&").unwrap();
@@ -186,12 +1565,373 @@ mod tests {
             Query {
                 tree: QueryExpression::Unary(
                     QueryTerm::Binary(
-                        QueryAtom {query_key: "a".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: "test".as_bytes()},
+                        Box::new(QueryTerm::Unary(QueryAtom {query_key: "a".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("test".as_bytes())})),
                         QueryOpTerm::AND,
-                        Box::new(QueryTerm::Unary( QueryAtom {query_key: "b".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: "what".as_bytes()})),
+                        Box::new(QueryTerm::Unary( QueryAtom {query_key: "b".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("what".as_bytes())})),
                     )
-                )
+                ),
+                stages: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_negated_group() {
+        let query = parse_only(|i| query(i), b"!(a=\"x\" || a=\"y\") && b=\"z\"").unwrap();
+        let atom = |key: &'static str, value: &'static str| QueryTerm::Unary(
+            QueryAtom {query_key: key.as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal(value.as_bytes())}
+        );
+        assert_eq!(
+            query,
+            Query {
+                tree: QueryExpression::Unary(
+                    QueryTerm::Binary(
+                        Box::new(QueryTerm::Not(Box::new(QueryTerm::Group(Box::new(
+                            QueryExpression::Binary(
+                                atom("a", "x"),
+                                QueryOpExpression::OR,
+                                Box::new(QueryExpression::Unary(atom("a", "y"))),
+                            )
+                        ))))),
+                        QueryOpTerm::AND,
+                        Box::new(atom("b", "z")),
+                    )
+                ),
+                stages: vec![],
             }
         );
     }
+
+    #[test]
+    fn test_parse_full_text() {
+        let parsed = parse_query("\"NullPointerException\" && verbosity=\"ERROR\"").unwrap();
+        assert_eq!(
+            parsed.tree,
+            QueryExpression::Unary(
+                QueryTerm::Binary(
+                    Box::new(QueryTerm::FullText("NullPointerException".as_bytes())),
+                    QueryOpTerm::AND,
+                    Box::new(QueryTerm::Unary(
+                        QueryAtom {query_key: "verbosity".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("ERROR".as_bytes())}
+                    )),
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_strings() {
+        let cases = [
+            ("a = 'x y'", "x y"),
+            ("a='say \"hi\"'", "say \"hi\""),
+            ("a=\"it's\"", "it's"),
+            ("a=\"\\\"\\\\\"", "\"\\"),
+            ("a='\\'\\n\\t'", "'\n\t"),
+            ("a=\"\\\\d+\"", "\\d+"),
+            ("a=\"\\u{53}napshot \\u{1F600}\"", "Snapshot \u{1F600}"),
+            ("a=''", ""),
+        ];
+        for (raw, value) in cases.iter() {
+            let atom = match parse_query(raw).unwrap().map(&unescape).tree {
+                QueryExpression::Unary(QueryTerm::Unary(atom)) => atom,
+                tree => panic!("Not an atom: {:?}", tree),
+            };
+            assert_eq!(atom.query_value, QueryValue::Literal(String::from(*value)), "Parsing {}", raw);
+        }
+        assert_eq!(
+            parse_query("'x' && f('a b')").unwrap().map(&unescape).tree,
+            QueryExpression::Unary(QueryTerm::Binary(
+                Box::new(QueryTerm::FullText(String::from("x"))),
+                QueryOpTerm::AND,
+                Box::new(QueryTerm::Call(String::from("f"), vec![String::from("a b")])),
+            ))
+        );
+        assert_eq!(unescape("class"), "class", "Text without escape sequences stays the same");
+    }
+
+    #[test]
+    fn test_parse_field_reference() {
+        let parsed = parse_query("end_ms > field(start_ms) && thread = field( application )").unwrap();
+        assert_eq!(
+            parsed.tree,
+            QueryExpression::Unary(QueryTerm::Binary(
+                Box::new(QueryTerm::Unary(
+                    QueryAtom {query_key: "end_ms".as_bytes(), query_constraint: QueryConstraint::GT, query_value: QueryValue::Field("start_ms".as_bytes())}
+                )),
+                QueryOpTerm::AND,
+                Box::new(QueryTerm::Unary(
+                    QueryAtom {query_key: "thread".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Field("application".as_bytes())}
+                )),
+            ))
+        );
+        assert_eq!(
+            parse_query("a=field").unwrap().tree,
+            QueryExpression::Unary(QueryTerm::Unary(
+                QueryAtom {query_key: "a".as_bytes(), query_constraint: QueryConstraint::EQ, query_value: QueryValue::Literal("field".as_bytes())}
+            )),
+            "A bare value is still a literal"
+        );
+    }
+
+    #[test]
+    fn test_parse_call() {
+        let parsed = parse_query("is_internal_ip( client ) && !business_hours() || in_subnet(client, \"10.0.0.0/8\") && exists(user)").unwrap();
+        assert_eq!(
+            parsed.tree,
+            QueryExpression::Binary(
+                QueryTerm::Binary(
+                    Box::new(QueryTerm::Call("is_internal_ip".as_bytes(), vec!["client".as_bytes()])),
+                    QueryOpTerm::AND,
+                    Box::new(QueryTerm::Not(Box::new(QueryTerm::Call("business_hours".as_bytes(), vec![])))),
+                ),
+                QueryOpExpression::OR,
+                Box::new(QueryExpression::Unary(QueryTerm::Binary(
+                    Box::new(QueryTerm::Call("in_subnet".as_bytes(), vec!["client".as_bytes(), "10.0.0.0/8".as_bytes()])),
+                    QueryOpTerm::AND,
+                    Box::new(QueryTerm::Unary(
+                        QueryAtom {query_key: "user".as_bytes(), query_constraint: QueryConstraint::EXISTS, query_value: QueryValue::Empty}
+                    )),
+                ))),
+            )
+        );
+        assert_eq!(
+            parse_query("business_hours (@time)").unwrap().tree,
+            QueryExpression::Unary(QueryTerm::Call("business_hours".as_bytes(), vec!["@time".as_bytes()]))
+        );
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            parse_query("@errors && (@time > \"2020/07/17\" || !@slow_requests)").unwrap().tree,
+            QueryExpression::Unary(QueryTerm::Binary(
+                Box::new(QueryTerm::Reference("errors".as_bytes())),
+                QueryOpTerm::AND,
+                Box::new(QueryTerm::Group(Box::new(QueryExpression::Binary(
+                    QueryTerm::Unary(QueryAtom {query_key: "@time".as_bytes(), query_constraint: QueryConstraint::GT, query_value: QueryValue::Literal("2020/07/17".as_bytes())}),
+                    QueryOpExpression::OR,
+                    Box::new(QueryExpression::Unary(QueryTerm::Not(Box::new(QueryTerm::Reference("slow_requests".as_bytes()))))),
+                )))),
+            ))
+        );
+        assert_eq!(
+            parse_query("@errors | head 1").unwrap(),
+            Query {tree: QueryExpression::Unary(QueryTerm::Reference("errors".as_bytes())), stages: vec![QueryStage::Head(1)]}
+        );
+    }
+
+    #[test]
+    fn test_parse_and_bind_parameters() {
+        let parsed = parse_query("client_id=$client && (@time >= $since || !thread = $client)").unwrap();
+        let atom = |key: &'static str, query_constraint: QueryConstraint, query_value: QueryValue<&'static [u8]>| {
+            QueryTerm::Unary(QueryAtom {query_key: key.as_bytes(), query_constraint, query_value})
+        };
+        assert_eq!(
+            parsed.tree,
+            QueryExpression::Unary(QueryTerm::Binary(
+                Box::new(atom("client_id", QueryConstraint::EQ, QueryValue::Parameter("client".as_bytes()))),
+                QueryOpTerm::AND,
+                Box::new(QueryTerm::Group(Box::new(QueryExpression::Binary(
+                    atom("@time", QueryConstraint::GE, QueryValue::Parameter("since".as_bytes())),
+                    QueryOpExpression::OR,
+                    Box::new(QueryExpression::Unary(QueryTerm::Not(Box::new(
+                        atom("thread", QueryConstraint::EQ, QueryValue::Parameter("client".as_bytes()))
+                    )))),
+                )))),
+            ))
+        );
+        let value = |name: &&[u8]| match *name {
+            b"client" => Some("abc\" || \"1".as_bytes()),
+            b"since" => Some("2020/07/17".as_bytes()),
+            _ => None,
+        };
+        assert_eq!(
+            parsed.tree.clone().bind_parameters(&value).unwrap(),
+            QueryExpression::Unary(QueryTerm::Binary(
+                Box::new(atom("client_id", QueryConstraint::EQ, QueryValue::Literal("abc\" || \"1".as_bytes()))),
+                QueryOpTerm::AND,
+                Box::new(QueryTerm::Group(Box::new(QueryExpression::Binary(
+                    atom("@time", QueryConstraint::GE, QueryValue::Literal("2020/07/17".as_bytes())),
+                    QueryOpExpression::OR,
+                    Box::new(QueryExpression::Unary(QueryTerm::Not(Box::new(
+                        atom("thread", QueryConstraint::EQ, QueryValue::Literal("abc\" || \"1".as_bytes()))
+                    )))),
+                )))),
+            )),
+            "Values are bound as they are, so quotes in them stay part of the value"
+        );
+        assert_eq!(parsed.tree.bind_parameters(&|name: &&[u8]| if *name == b"client" { Some(*name) } else { None }), Err("since".as_bytes()));
+    }
+
+    #[test]
+    fn test_query_json_round_trip() {
+        let parsed = parse_query("a in (x, y) && !b >= field(c) || \"say \\\"hi\\\"\" | sort -@time | timechart span=5m avg(l) as m by c | eval d = if(l > 1, substr(a, -2), 0)").unwrap().map(&unescape);
+        let json = serde_json::to_string(&parsed).unwrap();
+        assert!(json.contains(r#"{"FullText":"say \"hi\""}"#), "Values are written as text without escape sequences: {}", json);
+        let deserialized: Query<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, parsed);
+        assert_eq!(deserialized.to_string(), parsed.to_string());
+        let written: Query<String> = serde_json::from_str(r#"{
+                "tree": {"Unary": {"Unary": {"query_key": "class", "query_constraint": "EQ", "query_value": {"Literal": "A"}}}}
+            }"#).unwrap();
+        assert_eq!(written.to_string(), "class = \"A\"", "Stages can be left out");
+    }
+
+    #[test]
+    fn test_parse_stages() {
+        let parsed = parse_query("a=\"x\" || b=\"y\" | fields class, @time |sort -@time,class| head 10 | tail 2").unwrap();
+        assert_eq!(
+            parsed.stages,
+            vec![
+                QueryStage::Fields(vec!["class".as_bytes(), "@time".as_bytes()]),
+                QueryStage::Sort(vec![
+                    SortKey {key: "@time".as_bytes(), descending: true},
+                    SortKey {key: "class".as_bytes(), descending: false},
+                ]),
+                QueryStage::Head(10),
+                QueryStage::Tail(2),
+            ]
+        );
+        assert!(matches!(parsed.tree, QueryExpression::Binary(_, QueryOpExpression::OR, _)), "|| still separates alternatives");
+        let parsed = parse_query("a=\"x\" | stats count(), min( latency ), avg(latency) as mean by class, thread | head 3").unwrap();
+        assert_eq!(
+            parsed.stages,
+            vec![
+                QueryStage::Stats {
+                    aggregates: vec![
+                        Aggregate {function: AggregateFunction::Count, field: None, alias: None},
+                        Aggregate {function: AggregateFunction::Min, field: Some("latency".as_bytes()), alias: None},
+                        Aggregate {function: AggregateFunction::Avg, field: Some("latency".as_bytes()), alias: Some("mean".as_bytes())},
+                    ],
+                    by: vec!["class".as_bytes(), "thread".as_bytes()],
+                },
+                QueryStage::Head(3),
+            ]
+        );
+        assert_eq!(
+            parse_query("a=\"x\" | stats count(user)").unwrap().stages,
+            vec![QueryStage::Stats {aggregates: vec![Aggregate {function: AggregateFunction::Count, field: Some("user".as_bytes()), alias: None}], by: vec![]}]
+        );
+        assert_eq!(
+            parse_query("a=\"x\" | timechart span=5m count(), avg(latency) by verbosity").unwrap().stages,
+            vec![QueryStage::Timechart {
+                span: Span {amount: 5, unit: SpanUnit::Minute},
+                aggregates: vec![
+                    Aggregate {function: AggregateFunction::Count, field: None, alias: None},
+                    Aggregate {function: AggregateFunction::Avg, field: Some("latency".as_bytes()), alias: None},
+                ],
+                by: Some("verbosity".as_bytes()),
+            }]
+        );
+        assert_eq!(
+            parse_query("a=\"x\" | timechart span = 1d count()").unwrap().stages,
+            vec![QueryStage::Timechart {
+                span: Span {amount: 1, unit: SpanUnit::Day},
+                aggregates: vec![Aggregate {function: AggregateFunction::Count, field: None, alias: None}],
+                by: None,
+            }]
+        );
+        assert_eq!(parse_query("a=\"x | y\"").unwrap().stages, vec![], "Pipes in values do not start stages");
+    }
+
+    #[test]
+    fn test_parse_eval_stage() {
+        let field = |name: &'static str| EvalExpression::Field(name.as_bytes());
+        let number = |digits: &'static str| EvalExpression::Number(digits.as_bytes());
+        let binary = |left, operator, right| EvalExpression::Binary(Box::new(left), operator, Box::new(right));
+        assert_eq!(
+            parse_query("a=\"x\" | eval ms = int(latency) * 1000 - 1, short=substr( class,0 , 10 ) | head 1").unwrap().stages,
+            vec![
+                QueryStage::Eval(vec![
+                    EvalAssignment {
+                        field: "ms".as_bytes(),
+                        expression: binary(
+                            binary(EvalExpression::Call(EvalFunction::Int, vec![field("latency")]), EvalOperator::Multiply, number("1000")),
+                            EvalOperator::Subtract,
+                            number("1"),
+                        ),
+                    },
+                    EvalAssignment {
+                        field: "short".as_bytes(),
+                        expression: EvalExpression::Call(EvalFunction::Substr, vec![field("class"), number("0"), number("10")]),
+                    },
+                ]),
+                QueryStage::Head(1),
+            ]
+        );
+        assert_eq!(
+            parse_query("a=\"x\" | eval x = if(a+1 >= b*2, \"big\", -1)").unwrap().stages,
+            vec![QueryStage::Eval(vec![EvalAssignment {
+                field: "x".as_bytes(),
+                expression: EvalExpression::Call(EvalFunction::If, vec![
+                    binary(
+                        binary(field("a"), EvalOperator::Add, number("1")),
+                        EvalOperator::GreaterEqual,
+                        binary(field("b"), EvalOperator::Multiply, number("2")),
+                    ),
+                    EvalExpression::Text("big".as_bytes()),
+                    number("-1"),
+                ]),
+            }])]
+        );
+        assert_eq!(
+            parse_query("a=\"x\" | eval x = a-b").unwrap().stages,
+            vec![QueryStage::Eval(vec![EvalAssignment {field: "x".as_bytes(), expression: field("a-b")}])],
+            "Keys can contain '-', so subtraction needs whitespace"
+        );
+    }
+
+    #[test]
+    fn test_parse_query_errors() {
+        let cases = [
+            ("class=\"A\" && thread", 19, Expected::Element("operator")),
+            ("class=\"A\" thread=\"B\"", 10, Expected::Element("'&&', '||', '|' or the end of the query")),
+            ("(class=\"A\"", 10, Expected::Token(b')')),
+            ("class=\"A", 8, Expected::Token(b'"')),
+            ("class='A\"", 9, Expected::Token(b'\'')),
+            ("class=\"A\\\"", 10, Expected::Token(b'"')),
+            ("class=\"\\d\"", 8, Expected::Element("escape sequence")),
+            ("class=\"\\u{zz}\"", 8, Expected::Element("escape sequence")),
+            ("class=\"\\u{110000}\"", 8, Expected::Element("escape sequence")),
+            ("a=\"x\" && && b=\"y\"", 9, Expected::Element("key")),
+            ("a==\"x\"", 2, Expected::Element("value")),
+            ("class in \"A\"", 9, Expected::Token(b'(')),
+            ("a = field()", 10, Expected::Element("key")),
+            ("is_internal_ip(client", 21, Expected::Element("',' or ')'")),
+            ("is_internal_ip(client,)", 21, Expected::Element("',' or ')'")),
+            ("is_internal_ip(", 15, Expected::Element("argument")),
+            ("client_id = $", 13, Expected::Element("key")),
+            ("@errors x", 8, Expected::Element("operator")),
+            ("@ && a=b", 2, Expected::Element("operator")),
+            ("client_id in ($client)", 14, Expected::Element("value")),
+            ("", 0, Expected::Element("key")),
+            ("a=\"x\" | bogus 3", 8, Expected::Element("stage")),
+            ("a=\"x\" | head x", 13, Expected::Element("count")),
+            ("a=\"x\" | fields", 14, Expected::Element("key")),
+            ("a=\"x\" | fields a, | head 1", 16, Expected::Element("'&&', '||', '|' or the end of the query")),
+            ("a=\"x\" | stats sum(a)", 14, Expected::Element("aggregate function")),
+            ("a=\"x\" | stats avg()", 18, Expected::Element("key")),
+            ("a=\"x\" | stats count() by", 24, Expected::Element("key")),
+            ("a=\"x\" | timechart count()", 18, Expected::Element("span")),
+            ("a=\"x\" | eval x = foo(1)", 17, Expected::Element("function")),
+            ("a=\"x\" | eval x = substr(a)", 25, Expected::Token(b',')),
+            ("a=\"x\" | eval x = int(a, b)", 22, Expected::Token(b')')),
+            ("a=\"x\" | eval x = ", 17, Expected::Element("expression")),
+            ("a=\"x\" | eval x = 1 < 2 < 3", 23, Expected::Element("'&&', '||', '|' or the end of the query")),
+            ("a=\"x\" | timechart span=5 count()", 24, Expected::Element("span unit")),
+            ("a=\"x\" | timechart span=5w count()", 25, Expected::Element("span unit")),
+            ("a=\"x\" | timechart span=0s count()", 25, Expected::Element("span")),
+            ("a=\"x\" | timechart span=99999999999999999d count()", 41, Expected::Element("span")),
+            ("a=\"x\" | timechart span=1m count() by a, b", 38, Expected::Element("'&&', '||', '|' or the end of the query")),
+        ];
+        for (raw, offset, expected) in cases.iter() {
+            let error = parse_query(raw).unwrap_err();
+            assert_eq!((error.offset, &error.expected), (*offset, expected), "Parsing {}", raw);
+        }
+    }
+
+    #[test]
+    fn test_parse_query_surrounding_whitespace() {
+        assert!(parse_query("  a=\"x\" && b=\"y\"  ").is_ok());
+    }
 }
\ No newline at end of file
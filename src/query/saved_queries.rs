@@ -0,0 +1,275 @@
+//! Queries saved by name, which other queries refer to as `@name`
+use crate::query::query_ast::{is_key_char, parse_query, Query, QueryExpression, QueryTerm};
+use simple_error::{bail, SimpleError};
+use std::collections::HashMap;
+use std::str::{from_utf8, FromStr};
+
+/// Named queries, like debugging recipes shared between people looking at the same logs
+///
+/// Definitions are written one per line as `name = query`, e.g.
+///      errors_in_dispatcher = verbosity="ERROR" && thread="ImageManagerImpl-dispatcher"
+/// Blank lines and lines starting with `#` are skipped. A definition can refer to any other
+/// definition, as long as no definition ends up referring to itself.
+#[derive(Debug, Clone, Default)]
+pub struct SavedQueries {
+    queries: HashMap<String, String>,
+}
+
+impl SavedQueries {
+    pub fn new() -> Self {
+        SavedQueries {
+            queries: HashMap::new(),
+        }
+    }
+
+    /// The query saved as `name`, as it was written
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.queries.get(name).map(String::as_str)
+    }
+
+    /// Replace the references to saved queries in a parsed query by the filters of those queries
+    ///
+    /// A query that is nothing but a reference, like `@errors | head 5`, also takes over the
+    /// stages of the saved query, ahead of its own. Elsewhere only saved queries without
+    /// stages can be referred to. Fails if the filter grows beyond `MAX_EXPANDED_TERMS` terms.
+    pub(crate) fn expand<'a>(&'a self, query: Query<&'a [u8]>) -> Result<Query<&'a [u8]>, SimpleError> {
+        self.expand_query(query, &mut Expansion { active: Vec::new(), parsed: HashMap::new(), terms: 0 })
+    }
+
+    fn expand_query<'a>(&'a self, query: Query<&'a [u8]>, expansion: &mut Expansion<'a>) -> Result<Query<&'a [u8]>, SimpleError> {
+        if let QueryExpression::Unary(QueryTerm::Reference(name)) = query.tree {
+            let saved = self.parse_saved(name, expansion)?;
+            expansion.active.push(name);
+            let mut expanded = self.expand_query(saved, expansion)?;
+            expansion.active.pop();
+            expanded.stages.extend(query.stages);
+            return Ok(expanded)
+        }
+        Ok(Query { tree: self.expand_expression(query.tree, expansion)?, stages: query.stages })
+    }
+
+    /// Parse the query saved as `name`, where `active` are the saved queries being expanded
+    fn parse<'a>(&'a self, name: &[u8], active: &[&[u8]]) -> Result<Query<&'a [u8]>, SimpleError> {
+        let name_text = from_utf8(name).unwrap();
+        if active.contains(&name) {
+            bail!("Saved query @{} refers to itself", name_text)
+        }
+        match self.queries.get(name_text) {
+            // definitions were checked to parse when they were added
+            Some(raw) => Ok(parse_query(raw).map_err(SimpleError::from)?),
+            None => bail!("Unknown saved query @{}", name_text),
+        }
+    }
+
+    /// Parse the query saved as `name` once per expansion, as it can be referred to many times
+    fn parse_saved<'a>(&'a self, name: &'a [u8], expansion: &mut Expansion<'a>) -> Result<Query<&'a [u8]>, SimpleError> {
+        if let Some(saved) = expansion.parsed.get(name).filter(|_| !expansion.active.contains(&name)) {
+            return Ok(saved.clone())
+        }
+        let saved = self.parse(name, &expansion.active)?;
+        expansion.parsed.insert(name, saved.clone());
+        Ok(saved)
+    }
+
+    fn expand_expression<'a>(&'a self, expression: QueryExpression<&'a [u8]>, expansion: &mut Expansion<'a>) -> Result<QueryExpression<&'a [u8]>, SimpleError> {
+        Ok(match expression {
+            QueryExpression::Unary(term) => QueryExpression::Unary(self.expand_term(term, expansion)?),
+            QueryExpression::Binary(term, op, expression) => QueryExpression::Binary(
+                self.expand_term(term, expansion)?,
+                op,
+                Box::new(self.expand_expression(*expression, expansion)?),
+            ),
+        })
+    }
+
+    fn expand_term<'a>(&'a self, term: QueryTerm<&'a [u8]>, expansion: &mut Expansion<'a>) -> Result<QueryTerm<&'a [u8]>, SimpleError> {
+        expansion.terms += 1;
+        if expansion.terms > MAX_EXPANDED_TERMS {
+            bail!("Saved queries expand to a query of more than {} terms", MAX_EXPANDED_TERMS)
+        }
+        Ok(match term {
+            QueryTerm::Reference(name) => {
+                let saved = self.parse_saved(name, expansion)?;
+                if !saved.stages.is_empty() {
+                    bail!("Saved query @{} has stages, so it can only be used as a whole query", from_utf8(name).unwrap())
+                }
+                expansion.active.push(name);
+                let tree = self.expand_expression(saved.tree, expansion)?;
+                expansion.active.pop();
+                QueryTerm::Group(Box::new(tree))
+            },
+            QueryTerm::Binary(factor, op, term) => QueryTerm::Binary(
+                Box::new(self.expand_term(*factor, expansion)?),
+                op,
+                Box::new(self.expand_term(*term, expansion)?),
+            ),
+            QueryTerm::Not(factor) => QueryTerm::Not(Box::new(self.expand_term(*factor, expansion)?)),
+            QueryTerm::Group(expression) => QueryTerm::Group(Box::new(self.expand_expression(*expression, expansion)?)),
+            term => term,
+        })
+    }
+
+    /// Check the references of the query saved as `name` without expanding them
+    ///
+    /// `active` are the saved queries whose references are being checked, and `checked` tells
+    /// for every saved query checked so far whether it can be used inside another filter,
+    /// which is when it has no stages, even through a reference it consists of.
+    fn check<'a>(&'a self, name: &'a [u8], active: &mut Vec<&'a [u8]>, checked: &mut HashMap<&'a [u8], bool>) -> Result<bool, SimpleError> {
+        if let Some(inside) = checked.get(name) {
+            return Ok(*inside)
+        }
+        let query = self.parse(name, active)?;
+        active.push(name);
+        let inside = match &query.tree {
+            QueryExpression::Unary(QueryTerm::Reference(reference)) => self.check(reference, active, checked)?,
+            tree => {
+                let mut references = Vec::new();
+                references_in_expression(tree, &mut references);
+                for reference in references {
+                    if !self.check(reference, active, checked)? {
+                        bail!("Saved query @{} has stages, so it can only be used as a whole query", from_utf8(reference).unwrap())
+                    }
+                }
+                true
+            },
+        } && query.stages.is_empty();
+        active.pop();
+        checked.insert(name, inside);
+        Ok(inside)
+    }
+}
+
+/// Most terms a query may have once saved queries are expanded, as a query that refers
+/// to another one twice, which refers to another one twice, and so on, doubles at every step
+const MAX_EXPANDED_TERMS: usize = 10_000;
+
+/// State of expanding a query
+struct Expansion<'a> {
+    /// Saved queries being expanded, to find queries referring to themselves
+    active: Vec<&'a [u8]>,
+    /// Saved queries parsed so far
+    parsed: HashMap<&'a [u8], Query<&'a [u8]>>,
+    /// Terms of the expanded query so far
+    terms: usize,
+}
+
+fn references_in_expression<'a>(expression: &QueryExpression<&'a [u8]>, references: &mut Vec<&'a [u8]>) {
+    match expression {
+        QueryExpression::Unary(term) => references_in_term(term, references),
+        QueryExpression::Binary(term, _, expression) => {
+            references_in_term(term, references);
+            references_in_expression(expression, references);
+        },
+    }
+}
+
+fn references_in_term<'a>(term: &QueryTerm<&'a [u8]>, references: &mut Vec<&'a [u8]>) {
+    match term {
+        QueryTerm::Reference(name) => references.push(name),
+        QueryTerm::Binary(factor, _, term) => {
+            references_in_term(factor, references);
+            references_in_term(term, references);
+        },
+        QueryTerm::Not(factor) => references_in_term(factor, references),
+        QueryTerm::Group(expression) => references_in_expression(expression, references),
+        QueryTerm::Unary(_) | QueryTerm::FullText(_) | QueryTerm::Call(..) => {},
+    }
+}
+
+impl FromStr for SavedQueries {
+    type Err = SimpleError;
+
+    /// Read definitions written one per line as `name = query`
+    ///
+    /// Fails if a definition does not parse, refers to a query that is not defined or to
+    /// itself, or uses a query with stages inside its filter. References are only checked,
+    /// not expanded, so definitions building on each other load quickly.
+    fn from_str(definitions: &str) -> Result<Self, Self::Err> {
+        let mut saved = SavedQueries::new();
+        for (index, line) in definitions.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let (name, raw) = match line.split_once('=') {
+                Some((name, raw)) => (name.trim(), raw.trim()),
+                None => bail!("Expected a saved query as name = query on line {}", index + 1),
+            };
+            if name.is_empty() || !name.bytes().all(is_key_char) {
+                bail!("Invalid name for a saved query on line {}: {}", index + 1, name)
+            }
+            if let Err(err) = parse_query(raw) {
+                bail!("Unable to parse saved query {} on line {}: {}", name, index + 1, err)
+            }
+            if saved.queries.insert(String::from(name), String::from(raw)).is_some() {
+                bail!("Saved query {} is defined again on line {}", name, index + 1)
+            }
+        }
+        let mut checked = HashMap::new();
+        for name in saved.queries.keys() {
+            if let Err(err) = saved.check(name.as_bytes(), &mut Vec::new(), &mut checked) {
+                bail!("Unable to expand saved query {}: {}", name, err)
+            }
+        }
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::query_ast::unescape;
+
+    fn expanded(saved: &SavedQueries, raw: &str) -> Result<String, SimpleError> {
+        Ok(saved.expand(parse_query(raw).unwrap())?.map(&unescape).to_string())
+    }
+
+    #[test]
+    fn test_expand_saved_queries() -> Result<(), SimpleError> {
+        let saved: SavedQueries = "
+            # errors of the image manager
+            errors = verbosity=\"ERROR\"
+            errors_in_dispatcher = @errors && thread=\"ImageManagerImpl-dispatcher\"
+            recent_errors = @errors | sort -@time | head 5
+        ".parse()?;
+        assert_eq!(saved.get("errors"), Some("verbosity=\"ERROR\""));
+        assert_eq!(
+            expanded(&saved, "@errors_in_dispatcher || !@errors && @time > \"2020/07/17\"")?,
+            "((verbosity = \"ERROR\") && thread = \"ImageManagerImpl-dispatcher\") || !(verbosity = \"ERROR\") && @time > \"2020/07/17\""
+        );
+        assert_eq!(expanded(&saved, "@errors && @errors")?, "(verbosity = \"ERROR\") && (verbosity = \"ERROR\")", "A query can be used more than once");
+        assert_eq!(expanded(&saved, "@recent_errors | fields class")?, "verbosity = \"ERROR\" | sort -@time | head 5 | fields class");
+        assert!(expanded(&saved, "@recent_errors && class=A").is_err(), "Stages only apply to the whole query");
+        assert_eq!(expanded(&saved, "@errors")?, "verbosity = \"ERROR\"");
+        assert!(expanded(&saved, "@unknown").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_saved_queries_expanding_too_far() -> Result<(), SimpleError> {
+        let definitions = (1..=40).map(|n| format!("q{} = @q{} && @q{}", n, n - 1, n - 1)).collect::<Vec<_>>().join("\n");
+        let saved: SavedQueries = format!("q0 = x=1\n{}", definitions).parse()?;
+        assert_eq!(expanded(&saved, "@q2")?, "((x = \"1\") && (x = \"1\")) && ((x = \"1\") && (x = \"1\"))");
+        let err = expanded(&saved, "@q40").unwrap_err();
+        assert!(err.as_str().contains("more than 10000 terms"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_saved_queries() {
+        let cases = [
+            "errors verbosity=ERROR",
+            "bad name = verbosity=ERROR",
+            "errors = verbosity=",
+            "errors = verbosity=ERROR\nerrors = verbosity=WARN",
+            "errors = @warnings",
+            "a = @b\nb = x=1 || @a",
+            "a = !@a",
+            "a = @a | head 1",
+            "a = x=1 | head 1\nb = @a\nc = y=2 && @b",
+        ];
+        for definitions in cases.iter() {
+            assert!(definitions.parse::<SavedQueries>().is_err(), "{} is not valid", definitions);
+        }
+    }
+}
@@ -1,31 +1,84 @@
 //! Users provide queries that generate constraints that are used as filters
-use crate::constraint::{Constraint};
+use crate::constraint::{Comparison, Constraint, TimestampConstraint, VerbosityConstraint};
+use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
 use crate::parser::log_line_parse_result::LogLineParseResult;
-use crate::query::query_ast::{parse_query};
+use crate::parser::parser::Parser;
+use crate::parser::timestamp::Timestamp;
+use crate::parser::verbosity::Verbosity;
+use crate::query::query_ast::{is_key_char, parse_query, unescape, Aggregate, AggregateFunction, EvalExpression, QueryExpression, QueryStage, QueryTerm, QueryValue};
 use crate::query::query_ast;
-use crate::query::constraint_factory::constraint_factory;
-use simple_error::SimpleError;
+use crate::query::optimizer::optimize;
+use crate::query::constraint_factory::{program_factory, tree_checker, TIMESTAMP_KEY};
+use crate::query::program::Program;
+use crate::query::builder::{FieldBuilder, QueryBuilder};
+use crate::query::context::QueryContext;
+use crate::query::functions::QueryFunctions;
+use crate::query::pipeline::{stage_factory, Pipeline};
+use crate::query::stats::aggregate_name;
+use simple_error::{bail, try_with, SimpleError};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 /// User provided parsed query that understands what predicates exist for filtering
 /// Query Grammar:
-/// ```
+/// ```text
 /// 
-/// query :=    expr 
+/// query :=    expr stage*
 /// 
 /// expr  :=    term
 ///             term || expr
 /// 
-/// term  :=    atom
-///             atom && term
+/// term  :=    factor
+///             factor && term
+/// 
+/// factor :=   atom
+///             "text"
+///             !factor
+///             ( expr )
+///             key( [argument, ...] )
+///             @key
+/// 
+/// atom := key op value
+///         key op field( key )
+///         key in ( value, ... )
+///         key not in ( value, ... )
+///         exists( key )
+///         missing( key )
+/// 
+/// op := = | =i | =* | != | < | <= | > | >= | ~ | !~
+///       contains | startswith | endswith
+///       icontains | istartswith | iendswith
 /// 
-/// atom := key="value"
+/// stage := | fields key, ...
+///          | sort [-]key, ...
+///          | head count
+///          | tail count
+///          | stats aggregate, ... [by key, ...]
+///          | timechart span=duration aggregate, ... [by key]
+///          | eval key = operand, ...
 /// 
-/// key := [\w]+
-/// value := [\w]+
+/// aggregate := function( [key] ) [as key]
+/// function := count | min | max | avg
+/// duration := count s | count m | count h | count d
+/// 
+/// operand := sum [ comparison sum ]
+/// sum := product { + product | - product }
+/// product := unit { * unit | / unit | % unit }
+/// unit := "text" | number | key | call( operand, ... ) | ( operand )
+/// comparison := == | != | < | <= | > | >=
+/// call := int | num | round | abs | len | lower | upper | trim | substr | concat | if | coalesce
+/// 
+/// argument := key | string
+/// key := [\w.@-]+
+/// value := string | [\w.-]+ | $key
+/// string := "text" | 'text'
 /// ```
 ///
 pub struct Query {
-    constraints: Box<dyn Constraint>,
+    program: Program,
+    stages: Vec<QueryStage<String>>,
+    /// Canonical form of the query as parsed, before any restrictions were added
+    canonical: String,
 }
 
 impl Query {
@@ -36,27 +89,311 @@ impl Query {
      * Currently a query takes the form:
      *      field1="value1" && field2="value2"
      * 
-     * A query accepts simple conjunctions and disjunctions of constraints. 
-     * Conjunctions are higher priority than disjunctions.
+     * A query accepts conjunctions and disjunctions of constraints, which can be negated
+     * with ! and grouped with parentheses. Negation binds tightest, followed by
+     * conjunctions and then disjunctions.
+     * 
+     * `=i` tests for equality ignoring case, and `=*` matches a whole field against a
+     * glob such as "dispatcher-*".
+     * Besides equality, fields can be compared with !=, <, <=, > and >=. These compare
+     * numerically when both sides are numbers and lexicographically otherwise.
+     * `~` and `!~` test whether a field contains a match for a regular expression,
+     * which is compiled once when the query is created. `contains`, `startswith` and
+     * `endswith` test for substrings, and their `i` prefixed forms ignore case.
+     * `in` and `not in` test a field against a parenthesized list of values.
+     * `exists(key)` and `missing(key)` test whether a field was captured at all, which
+     * is useful for optional capture groups. A quoted string on its own is a full text
+     * search that passes when any captured field contains it. A call like
+     * `is_internal_ip(client)` runs a function registered with `with_functions`, and a
+     * placeholder like `$client` stands for a value given to `with_parameters`.
+     * `@name` stands for the filter of a query saved as `name`, see `with_context`.
      * 
-     * For now, only equality is supported, though this is changing.
+     * Strings are quoted with `"` or `'`, and can contain the escape sequences `\"`, `\'`,
+     * `\\`, `\n`, `\t` and `\u{1F600}`, so a regular expression is written as "\\d+".
+     * 
+     * The filter can be followed by pipeline stages separated with `|`, e.g.
+     *      verbosity="ERROR" | sort -@time | head 5 | fields class, content
+     * which are run by the `Pipeline` returned from `pipeline`.
+     * 
+     * The special key `@time` compares the timestamp of a log line, e.g.
+     *      @time >= "2020/07/17 23:00:00"
+     * and `@level` compares its verbosity by severity, e.g.
+     *      @level >= WARN
      */
     pub fn new(raw_query: &str) -> Result<Self, SimpleError> {
-        let parse_tree = parse_query(raw_query)?;
+        Self::with_context(raw_query, &QueryContext::new())
+    }
+
+    /// Create a query whose placeholders are replaced by the values of the parameters of the same name, e.g.
+    ///      client_id = $client
+    ///
+    /// Values are bound to the parsed query, so they can never be read as anything but a value,
+    /// even if they contain quotes. A placeholder without a value fails when the query is created.
+    pub fn with_parameters(raw_query: &str, parameters: &HashMap<String, String>) -> Result<Self, SimpleError> {
+        Self::with_context(raw_query, &QueryContext::new().parameters(parameters.clone()))
+    }
+
+    /// Create a query that can call the given functions by name, e.g.
+    ///      is_internal_ip(client) && !business_hours(@time)
+    ///
+    /// Calling a function that was not registered fails when the query is created.
+    pub fn with_functions(raw_query: &str, functions: &QueryFunctions) -> Result<Self, SimpleError> {
+        Self::with_context(raw_query, &QueryContext::new().functions(functions.clone()))
+    }
+
+    /// Create a query that can use the functions, parameters and saved queries of `context`
+    ///
+    /// References to saved queries like `@errors_in_dispatcher` are expanded first, then
+    /// placeholders are replaced by their values. A reference to a query that was not saved
+    /// fails when the query is created.
+    pub fn with_context(raw_query: &str, context: &QueryContext) -> Result<Self, SimpleError> {
+        let parse_tree = parse_query(raw_query).map_err(SimpleError::from)?;
+        let mut parse_tree = context.saved_queries.expand(parse_tree)?.map(&unescape);
+        let parameters = &context.parameters;
+        parse_tree.tree = match parse_tree.tree.bind_parameters(&|name| parameters.get(name).cloned()) {
+            Ok(tree) => tree,
+            Err(name) => bail!("No value given for parameter ${}", name),
+        };
+        Self::from_parse_tree(parse_tree, &context.functions)
+    }
+
+    /// Create a query from its parse tree written as JSON, e.g.
+    ///      {"tree": {"Unary": {"Unary": {"query_key": "class", "query_constraint": "EQ", "query_value": {"Literal": "A"}}}}}
+    ///
+    /// This allows tools to build queries without writing them as text. `stages` can be
+    /// left out for a query without a pipeline. Values are used as they are, so a value can
+    /// contain quotes or backslashes without escaping them, while keys must be ones the grammar
+    /// accepts. This is the JSON of a `query_ast::Query<String>`.
+    pub fn from_json(json: &str) -> Result<Self, SimpleError> {
+        let parse_tree: query_ast::Query<String> = try_with!(serde_json::from_str(json), "Unable to parse query from JSON");
+        Self::check_tree(&parse_tree.tree)?;
+        for stage in parse_tree.stages.iter() {
+            Self::check_stage(stage)?;
+        }
+        Self::from_parse_tree(parse_tree, &QueryFunctions::new())
+    }
+
+    /// Start building a query in Rust with a constraint on `key`, e.g.
+    ///      Query::field("class").eq("A").and(Query::field("@level").gte("WARN")).build()
+    ///
+    /// The query is the same as when the filter is written as text, so the example builds
+    /// `class = "A" && @level >= "WARN"`.
+    pub fn field(key: &str) -> FieldBuilder {
+        FieldBuilder::new(key)
+    }
+
+    /// Start building a query in Rust with a full text search for `value`
+    pub fn text(value: &str) -> QueryBuilder {
+        QueryBuilder::text(value)
+    }
+
+    /// Start building a query in Rust with a call of a registered function
+    pub fn call(name: &str, arguments: &[&str]) -> QueryBuilder {
+        QueryBuilder::call(name, arguments)
+    }
+
+    /// Create a query from a parse tree whose values are used as they are, with no escape sequences left
+    pub(crate) fn from_parse_tree(parse_tree: query_ast::Query<String>, functions: &QueryFunctions) -> Result<Self, SimpleError> {
+        for stage in parse_tree.stages.iter() {
+            Self::check_columns(stage)?;
+        }
+        let canonical = parse_tree.to_string();
         Ok(Self{
-            constraints: Self::generate_constraints(parse_tree),
+            canonical,
+            program: Self::generate_program(parse_tree.tree, functions)?,
+            stages: parse_tree.stages,
         })
     }
 
-    /// Perform the mapping from a query ast to a constraint program
-    fn generate_constraints(parse_tree: query_ast::Query<&[u8]>) -> Box<dyn Constraint> {
-        constraint_factory(parse_tree)
+    /// Reject keys the grammar would not have parsed, such as empty ones or ones with spaces
+    fn check_key(key: &str) -> Result<(), SimpleError> {
+        if key.is_empty() || !key.bytes().all(is_key_char) {
+            bail!("Invalid key \"{}\"", key)
+        }
+        Ok(())
+    }
+
+    /// Reject filters with keys the grammar would not have parsed
+    fn check_tree(expression: &QueryExpression<String>) -> Result<(), SimpleError> {
+        match expression {
+            QueryExpression::Unary(term) => Self::check_term(term),
+            QueryExpression::Binary(term, _, expression) => {
+                Self::check_term(term)?;
+                Self::check_tree(expression)
+            },
+        }
+    }
+
+    /// Reject factors with keys the grammar would not have parsed
+    fn check_term(term: &QueryTerm<String>) -> Result<(), SimpleError> {
+        match term {
+            QueryTerm::Unary(atom) => {
+                Self::check_key(&atom.query_key)?;
+                match &atom.query_value {
+                    QueryValue::Field(key) | QueryValue::Parameter(key) => Self::check_key(key),
+                    QueryValue::Literal(_) | QueryValue::List(_) | QueryValue::Empty => Ok(()),
+                }
+            },
+            QueryTerm::Binary(factor, _, term) => {
+                Self::check_term(factor)?;
+                Self::check_term(term)
+            },
+            QueryTerm::Not(factor) => Self::check_term(factor),
+            QueryTerm::Group(expression) => Self::check_tree(expression),
+            QueryTerm::Call(name, _) | QueryTerm::Reference(name) => Self::check_key(name),
+            QueryTerm::FullText(_) => Ok(()),
+        }
+    }
+
+    /// Reject stages the grammar would not have parsed, as they were not checked by the parser
+    fn check_stage(stage: &QueryStage<String>) -> Result<(), SimpleError> {
+        match stage {
+            QueryStage::Stats { aggregates, by } => {
+                Self::check_aggregates(aggregates)?;
+                for key in by.iter() {
+                    Self::check_key(key)?;
+                }
+            },
+            QueryStage::Timechart { span, aggregates, by } => {
+                Self::check_aggregates(aggregates)?;
+                if span.amount == 0 {
+                    bail!("Timechart span must not be zero")
+                }
+                if span.milliseconds().is_none() {
+                    bail!("Timechart span is too long")
+                }
+                if let Some(key) = by {
+                    Self::check_key(key)?;
+                }
+            },
+            QueryStage::Eval(assignments) => for assignment in assignments.iter() {
+                Self::check_key(&assignment.field)?;
+                Self::check_expression(&assignment.expression)?;
+            },
+            QueryStage::Fields(keys) => for key in keys.iter() {
+                Self::check_key(key)?;
+            },
+            QueryStage::Sort(keys) => for sort_key in keys.iter() {
+                Self::check_key(&sort_key.key)?;
+            },
+            QueryStage::Head(_) | QueryStage::Tail(_) => {},
+        }
+        Ok(())
+    }
+
+    /// Reject timechart stages with aggregates that would be output as the same column, or as `@time`
+    fn check_columns(stage: &QueryStage<String>) -> Result<(), SimpleError> {
+        if let QueryStage::Timechart { aggregates, .. } = stage {
+            let mut names = HashSet::new();
+            for name in aggregates.iter().map(aggregate_name) {
+                if name == TIMESTAMP_KEY || !names.insert(name.clone()) {
+                    bail!("Timechart has more than one column named {}", name)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that only `count` leaves out its field, and that fields and aliases are keys
+    fn check_aggregates(aggregates: &[Aggregate<String>]) -> Result<(), SimpleError> {
+        for aggregate in aggregates.iter() {
+            if aggregate.field.is_none() && aggregate.function != AggregateFunction::Count {
+                bail!("Aggregate {} needs a field", aggregate.function.name())
+            }
+            for key in aggregate.field.iter().chain(aggregate.alias.iter()) {
+                Self::check_key(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that fields are keys, numbers are numbers and functions are called with as many arguments as they take
+    fn check_expression(expression: &EvalExpression<String>) -> Result<(), SimpleError> {
+        match expression {
+            EvalExpression::Number(number) => if number.parse::<f64>().is_err() {
+                bail!("Invalid number {}", number)
+            },
+            EvalExpression::Call(function, arguments) => {
+                let (min, max) = function.arity();
+                if arguments.len() < min || max.is_some_and(|max| arguments.len() > max) {
+                    bail!("Function {} cannot be called with {} arguments", function.name(), arguments.len())
+                }
+                for argument in arguments.iter() {
+                    Self::check_expression(argument)?;
+                }
+            },
+            EvalExpression::Binary(left, _, right) => {
+                Self::check_expression(left)?;
+                Self::check_expression(right)?;
+            },
+            EvalExpression::Field(key) => Self::check_key(key)?,
+            EvalExpression::Text(_) => {},
+        }
+        Ok(())
+    }
+
+    /// Perform the mapping from the filter of a query ast to a constraint program, simplifying it on the way
+    ///
+    /// Every constraint is checked before simplifying, so an invalid one is reported even if
+    /// it would have been folded away.
+    fn generate_program<T: AsRef<[u8]> + PartialEq>(tree: query_ast::QueryExpression<T>, functions: &QueryFunctions) -> Result<Program, SimpleError> {
+        tree_checker(&tree, functions)?;
+        program_factory(optimize(tree), functions)
+    }
+
+    /// Build a fresh pipeline for the stages after the filter
+    ///
+    /// Log lines that pass `check` are pushed into the pipeline, which emits the records to output.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline::new(self.stages.iter().map(stage_factory).collect())
+    }
+
+    /// Resolve the fields this query refers to against the fields of a parser, so they are
+    /// looked up by position instead of by name
+    ///
+    /// Log lines produced by other parsers are still checked by looking fields up by name.
+    pub fn resolve_fields(mut self, parser: &dyn Parser<ParserResult = DefaultLogLineParseResult>) -> Self {
+        if let Some(field_names) = parser.get_field_names() {
+            self.program.resolve_fields(field_names);
+        }
+        self
+    }
+
+    /// Additionally require log lines to be written at or after `since`
+    pub fn since(self, since: Timestamp) -> Self {
+        self.restrict(Box::new(TimestampConstraint::new(Comparison::GreaterThanOrEqual, since)))
+    }
+
+    /// Additionally require log lines to be written before `until`
+    pub fn until(self, until: Timestamp) -> Self {
+        self.restrict(Box::new(TimestampConstraint::new(Comparison::LessThan, until)))
+    }
+
+    /// Additionally require log lines to be at least as severe as `min_level`
+    pub fn min_level(self, min_level: Verbosity) -> Self {
+        self.restrict(Box::new(VerbosityConstraint::new(Comparison::GreaterThanOrEqual, min_level)))
+    }
+
+    /// Require log lines to pass `constraint` on top of the existing constraints
+    fn restrict(mut self, constraint: Box<dyn Constraint>) -> Self {
+        self.program.and_line(constraint);
+        self
     }
 
     /**
      * Check that the given log line passes constraints specified in the query
      */
     pub fn check(&self, log_line: &dyn LogLineParseResult) -> bool {
-        self.constraints.check(log_line)
+        self.program.check(log_line)
+    }
+}
+
+impl fmt::Display for Query {
+    /// Write the query in a canonical form, which creates the same query when parsed again
+    ///
+    /// Queries that only differ in whitespace, quoting of values or the like are written the
+    /// same way, e.g. `class=A|head 1` is written as `class = "A" | head 1`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.canonical)
     }
-}
\ No newline at end of file
+}
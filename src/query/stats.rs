@@ -0,0 +1,407 @@
+//! Aggregation of records into one row per group
+use crate::constraint::compare_values;
+use crate::parser::default_log_line_parse_result::DefaultLogLineParseResult;
+use crate::parser::log_line_parse_result::LogLineParseResult;
+use crate::parser::timestamp::Timestamp;
+use crate::query::constraint_factory::TIMESTAMP_KEY;
+use crate::query::pipeline::{Record, Stage};
+use crate::query::query_ast::{Aggregate, AggregateFunction};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Running state of an aggregate over the records of a group
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Count(u64),
+    Min(Option<String>),
+    Max(Option<String>),
+    Avg {
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Accumulator {
+    pub fn new(function: AggregateFunction) -> Self {
+        match function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+            AggregateFunction::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+        }
+    }
+
+    /// Take a value of the aggregated field into account
+    ///
+    /// Minimum and maximum compare like `<` and `>` in a filter, while averages skip values
+    /// that are not numbers.
+    pub fn add(&mut self, value: &str) {
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Min(min) => if min.as_ref().map_or(true, |min| compare_values(value, min) == Ordering::Less) {
+                *min = Some(String::from(value))
+            },
+            Accumulator::Max(max) => if max.as_ref().map_or(true, |max| compare_values(value, max) == Ordering::Greater) {
+                *max = Some(String::from(value))
+            },
+            Accumulator::Avg { sum, count } => if let Ok(number) = value.parse::<f64>() {
+                *sum += number;
+                *count += 1;
+            },
+        }
+    }
+
+    /// The aggregated value, if any value was taken into account
+    pub fn result(&self) -> Option<String> {
+        match self {
+            Accumulator::Count(count) => Some(count.to_string()),
+            Accumulator::Min(value) | Accumulator::Max(value) => value.clone(),
+            Accumulator::Avg { sum, count } if *count > 0 => Some((sum / *count as f64).to_string()),
+            Accumulator::Avg { .. } => None,
+        }
+    }
+}
+
+/// Name of the field an aggregate is output as, like `count` or `avg(latency)`
+pub fn aggregate_name(aggregate: &Aggregate<String>) -> String {
+    match (&aggregate.alias, &aggregate.field) {
+        (Some(alias), _) => alias.clone(),
+        (None, Some(field)) => format!("{}({})", aggregate.function.name(), field),
+        (None, None) => String::from(aggregate.function.name()),
+    }
+}
+
+/// Accumulators for every group seen so far, in the order the groups were first seen
+pub struct Groups {
+    aggregates: Vec<Aggregate<String>>,
+    index: HashMap<Vec<String>, usize>,
+    groups: Vec<(Vec<String>, Vec<Accumulator>)>,
+}
+
+impl Groups {
+    pub fn new(aggregates: Vec<Aggregate<String>>) -> Self {
+        Groups {
+            aggregates,
+            index: HashMap::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// Position of the group with the given key, starting the group if it is new
+    pub fn start(&mut self, key: Vec<String>) -> usize {
+        if let Some(position) = self.index.get(&key) {
+            return *position
+        }
+        let accumulators = self.aggregates.iter().map(|aggregate| Accumulator::new(aggregate.function)).collect();
+        self.index.insert(key.clone(), self.groups.len());
+        self.groups.push((key, accumulators));
+        self.groups.len() - 1
+    }
+
+    /// Add a record to the group with the given key
+    pub fn add(&mut self, key: Vec<String>, record: &dyn LogLineParseResult) {
+        let position = self.start(key);
+        let accumulators = &mut self.groups[position].1;
+        for (aggregate, accumulator) in self.aggregates.iter().zip(accumulators.iter_mut()) {
+            match &aggregate.field {
+                Some(field) => if let Some(value) = record.get_field(field) {
+                    accumulator.add(value)
+                },
+                None => accumulator.add(""),
+            }
+        }
+    }
+
+    /// Take out every group with its aggregated values by name, in the order they were first seen
+    pub fn drain(&mut self) -> Vec<(Vec<String>, HashMap<String, String>)> {
+        self.index.clear();
+        let aggregates = &self.aggregates;
+        self.groups.drain(..).map(|(key, accumulators)| {
+            let values = aggregates.iter()
+                .zip(accumulators.iter())
+                .filter_map(|(aggregate, accumulator)| accumulator.result().map(|result| (aggregate_name(aggregate), result)))
+                .collect();
+            (key, values)
+        }).collect()
+    }
+}
+
+/// Replaces the records with one row of aggregates per distinct value of the `by` fields
+///
+/// Records that lack any of the `by` fields are left out. Without `by` fields there is
+/// exactly one row, even when there were no records.
+pub struct StatsStage {
+    by: Vec<String>,
+    groups: Groups,
+}
+
+impl StatsStage {
+    pub fn new(aggregates: Vec<Aggregate<String>>, by: Vec<String>) -> Self {
+        StatsStage {
+            by,
+            groups: Groups::new(aggregates),
+        }
+    }
+}
+
+impl Stage for StatsStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        let key: Option<Vec<String>> = self.by.iter().map(|field| record.get_field(field).cloned()).collect();
+        if let Some(key) = key {
+            self.groups.add(key, record.as_ref());
+        }
+        None
+    }
+
+    fn finish(&mut self) -> Vec<Record> {
+        if self.by.is_empty() {
+            self.groups.start(Vec::new());
+        }
+        let by = &self.by;
+        self.groups.drain().into_iter().map(|(key, mut values)| -> Record {
+            values.extend(by.iter().cloned().zip(key));
+            Box::new(DefaultLogLineParseResult::new(values))
+        }).collect()
+    }
+}
+
+/// Longest run of empty windows that `TimechartStage` fills in
+pub const MAX_GAP_WINDOWS: i64 = 1000;
+
+/// Replaces the records with one row of aggregates per time window of `span` milliseconds
+///
+/// Each row holds the start of its window as `@time`, and a column per aggregate named
+/// like in `stats`. With a `by` field there is a column per distinct value of the field
+/// instead, named after the value, or like `avg(latency): INFO` if there are several
+/// aggregates or the value is `@time`. Records without a timestamp or the `by` field are left out. Windows between
+/// the first and the last one get a row even without records, so that counts drop to zero
+/// instead of leaving gaps, unless more than `MAX_GAP_WINDOWS` of them follow each other.
+pub struct TimechartStage {
+    span: i64,
+    aggregates: Vec<Aggregate<String>>,
+    by: Option<String>,
+    windows: BTreeMap<i64, Groups>,
+    /// Keys of the groups seen in any window, in the order they were first seen
+    series: Vec<Vec<String>>,
+    /// The same keys as `series`, to tell quickly whether a key was seen
+    seen: HashSet<Vec<String>>,
+}
+
+impl TimechartStage {
+    pub fn new(span: i64, aggregates: Vec<Aggregate<String>>, by: Option<String>) -> Self {
+        let series = match by {
+            Some(_) => Vec::new(),
+            None => vec![Vec::new()],
+        };
+        TimechartStage {
+            span,
+            aggregates,
+            seen: series.iter().cloned().collect(),
+            series,
+            by,
+            windows: BTreeMap::new(),
+        }
+    }
+
+    /// Row holding the aggregates of every series in a window
+    fn row(&self, window: i64, mut groups: Groups) -> Record {
+        for key in self.series.iter() {
+            groups.start(key.clone());
+        }
+        let timestamp = Timestamp::from_milliseconds(window);
+        let mut values = HashMap::new();
+        values.insert(String::from(TIMESTAMP_KEY), timestamp.to_string());
+        for (key, aggregated) in groups.drain() {
+            for (name, value) in aggregated {
+                values.insert(self.column(name, &key), value);
+            }
+        }
+        Box::new(DefaultLogLineParseResult::with_timestamp(values, Some(timestamp)))
+    }
+
+    /// Name of the column holding an aggregate of a group
+    fn column(&self, name: String, key: &[String]) -> String {
+        match key.first() {
+            None => name,
+            Some(value) if self.aggregates.len() == 1 && value != TIMESTAMP_KEY => value.clone(),
+            Some(value) => format!("{}: {}", name, value),
+        }
+    }
+}
+
+impl Stage for TimechartStage {
+    fn process(&mut self, record: Record) -> Option<Record> {
+        let window = match record.get_timestamp() {
+            Some(timestamp) => {
+                let milliseconds = timestamp.milliseconds();
+                milliseconds - milliseconds.rem_euclid(self.span)
+            },
+            None => return None,
+        };
+        let key = match &self.by {
+            Some(field) => vec![record.get_field(field)?.clone()],
+            None => Vec::new(),
+        };
+        if !self.seen.contains(&key) {
+            self.seen.insert(key.clone());
+            self.series.push(key.clone());
+        }
+        let aggregates = &self.aggregates;
+        self.windows.entry(window)
+            .or_insert_with(|| Groups::new(aggregates.clone()))
+            .add(key, record.as_ref());
+        None
+    }
+
+    fn finish(&mut self) -> Vec<Record> {
+        let mut rows: Vec<Record> = Vec::new();
+        let mut previous: Option<i64> = None;
+        for (window, groups) in std::mem::take(&mut self.windows) {
+            if let Some(previous) = previous {
+                let gap = (window - previous) / self.span - 1;
+                if gap <= MAX_GAP_WINDOWS {
+                    for empty in 1..=gap {
+                        rows.push(self.row(previous + empty * self.span, Groups::new(self.aggregates.clone())));
+                    }
+                }
+            }
+            rows.push(self.row(window, groups));
+            previous = Some(window);
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::pipeline::Pipeline;
+
+    fn aggregate(function: AggregateFunction, field: Option<&str>, alias: Option<&str>) -> Aggregate<String> {
+        Aggregate { function, field: field.map(String::from), alias: alias.map(String::from) }
+    }
+
+    fn record(fields: &[(&str, &str)]) -> Record {
+        Box::new(DefaultLogLineParseResult::new(
+            fields.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+        ))
+    }
+
+    fn row(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+    }
+
+    fn run<S: Stage + 'static>(stage: S, records: Vec<Record>) -> Vec<HashMap<String, String>> {
+        let mut pipeline = Pipeline::new(vec![Box::new(stage)]);
+        let mut emitted = Vec::new();
+        for record in records {
+            pipeline.push(record, &mut |record| emitted.push(record.get_content().clone()));
+        }
+        pipeline.finish(&mut |record| emitted.push(record.get_content().clone()));
+        emitted
+    }
+
+    #[test]
+    fn test_accumulators() {
+        let mut accumulators: Vec<Accumulator> = [AggregateFunction::Count, AggregateFunction::Min, AggregateFunction::Max, AggregateFunction::Avg]
+            .iter()
+            .map(|function| Accumulator::new(*function))
+            .collect();
+        assert_eq!(accumulators.iter().map(Accumulator::result).collect::<Vec<_>>(), vec![Some(String::from("0")), None, None, None]);
+        for value in ["90", "500", "n/a", "7.5"].iter() {
+            for accumulator in accumulators.iter_mut() {
+                accumulator.add(value);
+            }
+        }
+        assert_eq!(
+            accumulators.iter().map(Accumulator::result).collect::<Vec<_>>(),
+            vec![Some(String::from("4")), Some(String::from("7.5")), Some(String::from("n/a")), Some(String::from("199.16666666666666"))],
+            "Numbers compare numerically and averages skip values that are not numbers"
+        );
+    }
+
+    #[test]
+    fn test_stats_stage_by_field() {
+        let stage = StatsStage::new(
+            vec![
+                aggregate(AggregateFunction::Count, None, None),
+                aggregate(AggregateFunction::Max, Some("latency"), None),
+                aggregate(AggregateFunction::Avg, Some("latency"), Some("mean")),
+            ],
+            vec![String::from("class")],
+        );
+        let emitted = run(stage, vec![
+            record(&[("class", "B"), ("latency", "10")]),
+            record(&[("class", "A")]),
+            record(&[("latency", "1000")]),
+            record(&[("class", "B"), ("latency", "30")]),
+        ]);
+        assert_eq!(emitted, vec![
+            row(&[("class", "B"), ("count", "2"), ("max(latency)", "30"), ("mean", "20")]),
+            row(&[("class", "A"), ("count", "1")]),
+        ]);
+    }
+
+    #[test]
+    fn test_stats_stage_without_groups() {
+        let stage = || StatsStage::new(vec![aggregate(AggregateFunction::Count, Some("user"), None)], Vec::new());
+        assert_eq!(run(stage(), vec![record(&[("user", "a")]), record(&[("class", "A")])]), vec![row(&[("count(user)", "1")])]);
+        assert_eq!(run(stage(), Vec::new()), vec![row(&[("count(user)", "0")])], "There is a row even without records");
+        let grouped = StatsStage::new(vec![aggregate(AggregateFunction::Count, None, None)], vec![String::from("class")]);
+        assert_eq!(run(grouped, Vec::new()), Vec::<HashMap<String, String>>::new());
+    }
+
+    fn at(time: &str, verbosity: Option<&str>) -> Record {
+        let content = verbosity.iter().map(|verbosity| (String::from("verbosity"), String::from(*verbosity))).collect();
+        Box::new(DefaultLogLineParseResult::with_timestamp(content, time.parse::<Timestamp>().ok()))
+    }
+
+    #[test]
+    fn test_timechart_stage() {
+        let records = || vec![
+            at("2020/07/17 23:00:10", Some("INFO")),
+            at("2020/07/17 23:00:59.999", Some("ERROR")),
+            at("2020/07/17 23:01:00", Some("INFO")),
+            at("not a time", Some("INFO")),
+            at("2020/07/17 23:03:30", Some("INFO")),
+            at("2020/07/17 23:03:31", None),
+        ];
+        let stage = TimechartStage::new(60_000, vec![aggregate(AggregateFunction::Count, None, None)], None);
+        assert_eq!(run(stage, records()), vec![
+            row(&[("@time", "2020/07/17 23:00:00"), ("count", "2")]),
+            row(&[("@time", "2020/07/17 23:01:00"), ("count", "1")]),
+            row(&[("@time", "2020/07/17 23:02:00"), ("count", "0")]),
+            row(&[("@time", "2020/07/17 23:03:00"), ("count", "2")]),
+        ], "Lines without a timestamp are left out and empty windows are filled in");
+
+        let stage = TimechartStage::new(120_000, vec![aggregate(AggregateFunction::Count, None, None)], Some(String::from("verbosity")));
+        assert_eq!(run(stage, records()), vec![
+            row(&[("@time", "2020/07/17 23:00:00"), ("INFO", "2"), ("ERROR", "1")]),
+            row(&[("@time", "2020/07/17 23:02:00"), ("INFO", "1"), ("ERROR", "0")]),
+        ]);
+
+        let stage = TimechartStage::new(
+            3_600_000,
+            vec![aggregate(AggregateFunction::Count, None, None), aggregate(AggregateFunction::Max, Some("verbosity"), Some("top"))],
+            Some(String::from("verbosity")),
+        );
+        assert_eq!(run(stage, records()), vec![
+            row(&[("@time", "2020/07/17 23:00:00"), ("count: INFO", "3"), ("top: INFO", "INFO"), ("count: ERROR", "1"), ("top: ERROR", "ERROR")]),
+        ]);
+        let stage = TimechartStage::new(60_000, vec![aggregate(AggregateFunction::Count, None, None)], Some(String::from("verbosity")));
+        assert_eq!(run(stage, vec![at("2020/07/17 23:00:10", Some("@time")), at("2020/07/17 23:00:20", Some("INFO"))]), vec![
+            row(&[("@time", "2020/07/17 23:00:00"), ("count: @time", "1"), ("INFO", "1")]),
+        ], "A value named like the window column does not overwrite it");
+
+        let stage = TimechartStage::new(60_000, vec![aggregate(AggregateFunction::Count, None, None)], None);
+        assert_eq!(run(stage, Vec::new()), Vec::<HashMap<String, String>>::new());
+
+        let stage = TimechartStage::new(1000, vec![aggregate(AggregateFunction::Count, None, None)], None);
+        assert_eq!(run(stage, vec![at("1971/01/01", None), at("2020/07/17 23:00:00", None), at("2020/07/17 23:00:02", None)]), vec![
+            row(&[("@time", "1971/01/01 00:00:00"), ("count", "1")]),
+            row(&[("@time", "2020/07/17 23:00:00"), ("count", "1")]),
+            row(&[("@time", "2020/07/17 23:00:01"), ("count", "0")]),
+            row(&[("@time", "2020/07/17 23:00:02"), ("count", "1")]),
+        ], "Long gaps are not filled in");
+    }
+}